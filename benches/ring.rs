@@ -0,0 +1,232 @@
+//! Compares handing audio from librespot's thread to songbird's through the ring buffer with
+//! the per-frame `sync_channel` the sink used before it.
+//!
+//! Run with `cargo bench --bench ring`. Two measurements are made:
+//!
+//! - Throughput: both ends run flat out on their own threads, moving packets the size the
+//!   resampler writes and reading the size songbird takes per frame.
+//! - CPU time per stream: several streams play in real time, read every 20 ms like songbird
+//!   does, and the CPU time the process spent is split between them. Only the hand-over is
+//!   measured, decoding and resampling cost the same either way.
+
+// Not all of the ring is benchmarked, and checking all targets builds its tests without a harness
+#[allow(dead_code, unused_imports)]
+#[path = "../src/lib/ring.rs"]
+mod ring;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A minute of 48 kHz stereo audio.
+const FRAMES: usize = 48_000 * 60;
+/// Frames the resampler writes at once with its default chunk size.
+const PACKET_FRAMES: usize = 1120;
+/// Frames songbird reads at once, 20 ms.
+const READ_FRAMES: usize = 960;
+/// About 100 ms of audio.
+const LIMIT_SAMPLES: usize = 9600;
+const RUNS: usize = 7;
+
+/// Streams played at once when measuring CPU time, like as many guilds.
+const STREAMS: usize = 8;
+/// How long they play.
+const PLAY: Duration = Duration::from_secs(5);
+const READ_INTERVAL: Duration = Duration::from_millis(20);
+
+fn main() {
+    report("ring buffer, one push per packet", ring_buffer);
+    report("sync_channel, one send per frame", channel);
+
+    report_cpu("ring buffer, one push per packet", ring_buffer_stream);
+    report_cpu("sync_channel, one send per frame", channel_stream);
+}
+
+/// Prints the median time per frame over a few runs.
+fn report(name: &str, run: fn() -> Duration) {
+    let mut times: Vec<Duration> = (0..RUNS).map(|_| run()).collect();
+    times.sort();
+    let median = times[RUNS / 2];
+
+    println!(
+        "{:<36} {:>8.1} ns/frame, a minute of audio in {:>7.2} ms",
+        name,
+        median.as_nanos() as f64 / FRAMES as f64,
+        median.as_secs_f64() * 1000.0
+    );
+}
+
+fn ring_buffer() -> Duration {
+    let (mut producer, mut consumer) = ring::channel(LIMIT_SAMPLES);
+    let start = Instant::now();
+
+    let writer = thread::spawn(move || {
+        let packet = vec![0.5; PACKET_FRAMES * 2];
+        let mut left = FRAMES * 2;
+
+        while left > 0 {
+            let mut samples = &packet[..packet.len().min(left)];
+            while !samples.is_empty() {
+                let pushed = producer.push(samples);
+                samples = &samples[pushed..];
+                left -= pushed;
+                if pushed == 0 {
+                    thread::yield_now();
+                }
+            }
+        }
+    });
+
+    let mut out = vec![0.0; READ_FRAMES * 2];
+    let mut read = 0;
+    while read < FRAMES * 2 {
+        let popped = consumer.pop(&mut out);
+        read += popped;
+        if popped == 0 {
+            thread::yield_now();
+        }
+    }
+
+    writer.join().unwrap();
+    start.elapsed()
+}
+
+fn channel() -> Duration {
+    let (sender, receiver) = sync_channel::<[f32; 2]>(PACKET_FRAMES);
+    let start = Instant::now();
+
+    let writer = thread::spawn(move || {
+        for _ in 0..FRAMES {
+            sender.send([0.5, 0.5]).unwrap();
+        }
+    });
+
+    // Blocks for the first frame and takes what else is there, like the old sink's read
+    let mut out = vec![[0.0; 2]; READ_FRAMES];
+    let mut read = 0;
+    while read < FRAMES {
+        out[0] = receiver.recv().unwrap();
+        read += 1;
+
+        for frame in out.iter_mut().skip(1) {
+            match receiver.try_recv() {
+                Ok(received) => *frame = received,
+                Err(_) => break,
+            }
+            read += 1;
+        }
+    }
+
+    writer.join().unwrap();
+    start.elapsed()
+}
+
+/// Plays `STREAMS` streams in real time and prints the CPU time each of them cost.
+fn report_cpu(name: &str, stream: fn()) {
+    let start = cpu_time();
+    let streams: Vec<_> = (0..STREAMS).map(|_| thread::spawn(stream)).collect();
+    for stream in streams {
+        stream.join().unwrap();
+    }
+    let per_stream = (cpu_time() - start) / STREAMS as u32;
+
+    println!(
+        "{:<36} {:>8.2} ms of CPU time per stream and minute of audio, {:.3} % of a core",
+        name,
+        per_stream.as_secs_f64() * 1000.0 * 60.0 / PLAY.as_secs_f64(),
+        per_stream.as_secs_f64() * 100.0 / PLAY.as_secs_f64()
+    );
+}
+
+/// User and system CPU time of the whole process so far.
+fn cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    let usage = unsafe {
+        assert_eq!(libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()), 0);
+        usage.assume_init()
+    };
+
+    let time = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Calls `read` every 20 ms for as long as the streams play.
+fn read_in_real_time(mut read: impl FnMut()) {
+    let start = Instant::now();
+    let mut next = start;
+
+    while next - start < PLAY {
+        next += READ_INTERVAL;
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        read();
+    }
+}
+
+/// Wakes the writer when audio is taken out, as the sink does.
+#[derive(Default)]
+struct Room {
+    taken: Mutex<u64>,
+    condvar: Condvar,
+}
+
+fn ring_buffer_stream() {
+    let (mut producer, mut consumer) = ring::channel(LIMIT_SAMPLES);
+    let room = Arc::new(Room::default());
+    let done = Arc::new(AtomicBool::new(false));
+
+    let writer = {
+        let (room, done) = (room.clone(), done.clone());
+        thread::spawn(move || {
+            let packet = vec![0.5; PACKET_FRAMES * 2];
+            while !done.load(Ordering::Relaxed) {
+                let mut samples = &packet[..];
+                while !samples.is_empty() && !done.load(Ordering::Relaxed) {
+                    let taken = *room.taken.lock().unwrap();
+                    let pushed = producer.push(samples);
+                    samples = &samples[pushed..];
+                    if pushed == 0 {
+                        let guard = room.taken.lock().unwrap();
+                        let _ = room
+                            .condvar
+                            .wait_timeout_while(guard, READ_INTERVAL, |now| *now == taken)
+                            .unwrap();
+                    }
+                }
+            }
+        })
+    };
+
+    let mut out = vec![0.0; READ_FRAMES * 2];
+    read_in_real_time(|| {
+        consumer.pop(&mut out);
+        *room.taken.lock().unwrap() += 1;
+        room.condvar.notify_all();
+    });
+
+    done.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
+
+fn channel_stream() {
+    let (sender, receiver) = sync_channel::<[f32; 2]>(PACKET_FRAMES);
+
+    let writer = thread::spawn(move || while sender.send([0.5, 0.5]).is_ok() {});
+
+    let mut out = vec![[0.0; 2]; READ_FRAMES];
+    read_in_real_time(|| {
+        out[0] = receiver.recv().unwrap();
+        for frame in out.iter_mut().skip(1) {
+            match receiver.try_recv() {
+                Ok(received) => *frame = received,
+                Err(_) => break,
+            }
+        }
+    });
+
+    drop(receiver);
+    writer.join().unwrap();
+}
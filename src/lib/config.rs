@@ -1,36 +1,1314 @@
 use figment::{
+    error::Kind,
     providers::{Env, Format, Toml},
-    Error, Figment,
+    value::{Dict, Map},
+    Error, Figment, Metadata, Provider,
 };
-use serde::Deserialize;
+use librespot::playback::{
+    config::{Bitrate, NormalisationMethod, NormalisationType, PlayerConfig, VolumeCtrl},
+    mixer::MixerConfig,
+    player::duration_to_coefficient,
+};
+use serde::{de, Deserialize, Deserializer};
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const CONFIG_PATH: &str = "config.toml";
+
+/// Settings that may also be read from the file named by a `<SETTING>_FILE` environment
+/// variable, like `DISCORD_TOKEN_FILE=/run/secrets/discord_token`.
+pub const SECRET_FILE_SETTINGS: &[&str] =
+    &["discord_token", "spotify_username", "spotify_password"];
 
 #[derive(Deserialize, Clone)]
 pub struct Config {
+    /// Only needed by the Discord bot, not when streaming to a pipe.
     #[serde(alias = "DISCORD_TOKEN")]
+    #[serde(default)]
     pub discord_token: String,
+    /// How the Spotify account logs in when no cached credentials are available.
+    #[serde(alias = "SPOTIFY_AUTH_MODE")]
+    #[serde(default)]
+    pub spotify_auth_mode: AuthMode,
+    /// Spotify account of the default profile, unused when `[[profile]]`s are configured.
     #[serde(alias = "SPOTIFY_USERNAME")]
-    pub spotify_username: String,
+    pub spotify_username: Option<String>,
+    /// Only needed until the first successful login, after which the reusable credentials
+    /// cached by librespot are used.
     #[serde(alias = "SPOTIFY_PASSWORD")]
-    pub spotify_password: String,
-    #[serde(alias = "DISCORD_USER_ID")]
-    pub discord_user_id: u64,
+    pub spotify_password: Option<String>,
+    /// Discord users to follow, in order of priority. When several of them are in voice, the
+    /// one listed first wins.
+    #[serde(
+        alias = "discord_user_id",
+        alias = "DISCORD_USER_ID",
+        alias = "DISCORD_USER_IDS"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub discord_user_ids: Vec<u64>,
+    /// Voice or stage channels in other guilds the stream is played into as well, next to the
+    /// channel of the followed user.
+    #[serde(
+        alias = "broadcast_channel_id",
+        alias = "BROADCAST_CHANNEL_ID",
+        alias = "BROADCAST_CHANNEL_IDS"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub broadcast_channel_ids: Vec<u64>,
     #[serde(alias = "SPOTIFY_BOT_AUTOPLAY")]
     pub spotify_bot_autoplay: bool,
     #[serde(alias = "SPOTIFY_DEVICE_NAME")]
     #[serde(default = "default_spotify_device_name")]
     pub spotify_device_name: String,
+    /// Bitrate to stream from Spotify at, one of 96, 160 or 320 kbps.
+    #[serde(alias = "SPOTIFY_BITRATE")]
+    #[serde(default = "default_spotify_bitrate")]
+    pub spotify_bitrate: u16,
+    #[serde(alias = "SPOTIFY_GAPLESS")]
+    #[serde(default = "default_spotify_gapless")]
+    pub spotify_gapless: bool,
+    #[serde(alias = "SPOTIFY_NORMALISATION")]
+    #[serde(default)]
+    pub spotify_normalisation: bool,
+    /// `album`, `track` or `auto`.
+    #[serde(alias = "SPOTIFY_NORMALISATION_TYPE")]
+    #[serde(default, deserialize_with = "from_str")]
+    pub spotify_normalisation_type: NormalisationType,
+    /// `basic` or `dynamic`, the latter also applies a limiter.
+    #[serde(alias = "SPOTIFY_NORMALISATION_METHOD")]
+    #[serde(default, deserialize_with = "from_str")]
+    pub spotify_normalisation_method: NormalisationMethod,
+    #[serde(alias = "SPOTIFY_NORMALISATION_PREGAIN_DB")]
+    #[serde(default)]
+    pub spotify_normalisation_pregain_db: f64,
+    /// Level above which the dynamic limiter kicks in.
+    #[serde(alias = "SPOTIFY_NORMALISATION_THRESHOLD_DBFS")]
+    #[serde(default = "default_spotify_normalisation_threshold_dbfs")]
+    pub spotify_normalisation_threshold_dbfs: f64,
+    #[serde(alias = "SPOTIFY_NORMALISATION_ATTACK_MS")]
+    #[serde(default = "default_spotify_normalisation_attack_ms")]
+    pub spotify_normalisation_attack_ms: u64,
+    #[serde(alias = "SPOTIFY_NORMALISATION_RELEASE_MS")]
+    #[serde(default = "default_spotify_normalisation_release_ms")]
+    pub spotify_normalisation_release_ms: u64,
+    #[serde(alias = "SPOTIFY_NORMALISATION_KNEE_DB")]
+    #[serde(default = "default_spotify_normalisation_knee_db")]
+    pub spotify_normalisation_knee_db: f64,
+    /// How the Spotify volume slider maps to loudness: `log`, `cubic`, `linear` or `fixed`.
+    #[serde(alias = "SPOTIFY_VOLUME_CURVE")]
+    #[serde(default)]
+    pub spotify_volume_curve: VolumeCurve,
+    /// Directory for every cache that doesn't have its own directory configured.
+    #[serde(alias = "CACHE_DIR")]
+    pub cache_dir: Option<String>,
+    #[serde(alias = "CACHE_CREDENTIALS_DIR")]
+    pub cache_credentials_dir: Option<String>,
+    #[serde(alias = "CACHE_VOLUME_DIR")]
+    pub cache_volume_dir: Option<String>,
+    #[serde(alias = "CACHE_AUDIO_DIR")]
+    pub cache_audio_dir: Option<String>,
+    /// Whether to cache audio files at all, credentials and volume are cached regardless.
+    #[serde(alias = "CACHE_AUDIO")]
+    #[serde(default = "default_cache_audio")]
+    pub cache_audio: bool,
+    /// Size limit of the audio cache in bytes, given as e.g. `500MiB` or `4GB`.
+    #[serde(alias = "CACHE_SIZE_LIMIT")]
+    #[serde(default = "default_cache_size_limit", deserialize_with = "size")]
+    pub cache_size_limit: u64,
+    /// Audio buffered between librespot and Discord. Higher values ride out more network and
+    /// CPU hiccups, lower ones make controls feel more immediate.
+    #[serde(alias = "AUDIO_TARGET_LATENCY_MS")]
+    #[serde(default = "default_audio_target_latency_ms")]
+    pub audio_target_latency_ms: u64,
+    /// Fade when playback pauses, resumes or stops, so the waveform isn't cut mid-cycle.
+    #[serde(alias = "AUDIO_FADE_MS")]
+    #[serde(default = "default_audio_fade_ms")]
+    pub audio_fade_ms: u64,
+    /// Resampler from Spotify's 44.1 kHz to Discord's 48 kHz, `fft` or `sinc`.
+    #[serde(alias = "AUDIO_RESAMPLER")]
+    #[serde(default)]
+    pub audio_resampler: ResamplerEngine,
+    /// `fast`, `balanced` or `best`, trading CPU time and latency for fidelity.
+    #[serde(alias = "AUDIO_RESAMPLER_QUALITY")]
+    #[serde(default)]
+    pub audio_resampler_quality: ResamplerQuality,
+    /// Integrated loudness the stream is adjusted towards. Unset leaves the loudness alone and
+    /// disables the limiter.
+    #[serde(alias = "AUDIO_LOUDNESS_TARGET_LUFS")]
+    pub audio_loudness_target_lufs: Option<f64>,
+    /// True peak the limiter keeps the stream under.
+    #[serde(alias = "AUDIO_CEILING_DBTP")]
+    #[serde(default = "default_audio_ceiling_dbtp")]
+    pub audio_ceiling_dbtp: f64,
+    /// Overlap between a track that plays to its end and the next one, 0 for a hard cut.
+    #[serde(alias = "AUDIO_CROSSFADE_MS")]
+    #[serde(default)]
+    pub audio_crossfade_ms: u64,
+    /// Whether to record what each player sends to Discord, see `RecorderConfig`.
+    #[serde(alias = "RECORDER_ENABLED")]
+    #[serde(default)]
+    pub recorder_enabled: bool,
+    /// Directory recordings are written to.
+    #[serde(alias = "RECORDER_DIR")]
+    #[serde(default = "default_recorder_dir")]
+    pub recorder_dir: String,
+    /// `wav` or `opus`.
+    #[serde(alias = "RECORDER_FORMAT")]
+    #[serde(default)]
+    pub recorder_format: RecorderFormat,
+    /// Per-guild settings from `[guild.<id>]` tables, keyed by guild ID.
+    #[serde(alias = "GUILD")]
+    #[serde(default)]
+    pub guild: HashMap<String, GuildConfig>,
+    /// Independent Spotify accounts from `[[profile]]` tables, each with its own followed users.
+    #[serde(alias = "PROFILE")]
+    #[serde(default)]
+    pub profile: Vec<ProfileConfig>,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct ProfileConfig {
+    /// Identifies the profile in logs, also names its credentials and volume cache directory.
+    pub name: String,
+    /// Falls back to the top-level `spotify_auth_mode`.
+    pub spotify_auth_mode: Option<AuthMode>,
+    pub spotify_username: Option<String>,
+    pub spotify_password: Option<String>,
+    /// Files to read the credentials from instead, like the `*_FILE` environment variables.
+    pub spotify_username_file: Option<PathBuf>,
+    pub spotify_password_file: Option<PathBuf>,
+    #[serde(alias = "discord_user_id")]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub discord_user_ids: Vec<u64>,
+    #[serde(alias = "broadcast_channel_id")]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub broadcast_channel_ids: Vec<u64>,
+    /// Falls back to the top-level `spotify_device_name`.
+    pub spotify_device_name: Option<String>,
+    /// Falls back to the top-level `spotify_bot_autoplay`.
+    pub spotify_bot_autoplay: Option<bool>,
+}
+
+/// A Spotify account together with the Discord users whose voice channel it follows.
+#[derive(Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub spotify_auth_mode: AuthMode,
+    /// Unknown until the first login in discovery mode.
+    pub spotify_username: Option<String>,
+    pub spotify_password: Option<String>,
+    pub discord_user_ids: Vec<u64>,
+    /// Channels the stream is broadcast to besides the followed user's.
+    pub broadcast_channel_ids: Vec<u64>,
+    pub spotify_device_name: String,
+    pub spotify_bot_autoplay: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Log in with the configured username and password.
+    #[default]
+    Password,
+    /// Advertise the Connect device on the local network and receive credentials from the
+    /// Spotify app it is first selected in.
+    Discovery,
+}
+
+/// Name of the profile built from the top-level settings when no `[[profile]]`s are configured.
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct GuildConfig {
+    /// Voice channels Aoede may join in this guild. Empty allows every channel.
+    #[serde(default)]
+    pub allowed_voice_channels: Vec<u64>,
+    /// Volume of the stream in this guild, where 1.0 is unchanged.
+    #[serde(default = "default_volume")]
+    pub default_volume: f32,
+    /// Text channel to announce the currently playing track in.
+    pub announcement_channel: Option<u64>,
+    /// Whether Aoede may follow users into this guild at all.
+    #[serde(default = "default_follow")]
+    pub follow: bool,
+    /// Effects applied to the stream in this guild, in order.
+    #[serde(default)]
+    pub effects: Vec<EffectConfig>,
+}
+
+/// An effect from a guild's `[[guild.<id>.effects]]` tables, chosen by its `type`.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EffectConfig {
+    /// Boosts or cuts a band around `frequency`, the higher `q` the narrower.
+    Eq {
+        frequency: f64,
+        gain_db: f64,
+        #[serde(default = "default_eq_q")]
+        q: f64,
+    },
+    /// Boosts or cuts everything below `frequency`.
+    Bass {
+        #[serde(default = "default_bass_frequency")]
+        frequency: f64,
+        gain_db: f64,
+    },
+    /// Reduces the level above `threshold_db` by `ratio`.
+    Compressor {
+        threshold_db: f64,
+        ratio: f64,
+        #[serde(default = "default_compressor_knee_db")]
+        knee_db: f64,
+        #[serde(default = "default_compressor_attack_ms")]
+        attack_ms: f64,
+        #[serde(default = "default_compressor_release_ms")]
+        release_ms: f64,
+        #[serde(default)]
+        makeup_db: f64,
+    },
+}
+
+impl Default for GuildConfig {
+    fn default() -> Self {
+        GuildConfig {
+            allowed_voice_channels: Vec::new(),
+            default_volume: default_volume(),
+            announcement_channel: None,
+            follow: default_follow(),
+            effects: Vec::new(),
+        }
+    }
+}
+
+impl GuildConfig {
+    /// Whether Aoede may join the given voice channel of this guild.
+    pub fn may_join(&self, channel_id: u64) -> bool {
+        self.follow
+            && (self.allowed_voice_channels.is_empty()
+                || self.allowed_voice_channels.contains(&channel_id))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResamplerEngine {
+    /// Synchronous FFT resampler, cheap and exact for fixed rates.
+    #[default]
+    Fft,
+    /// Windowed sinc interpolation, more expensive.
+    Sinc,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResamplerQuality {
+    Fast,
+    #[default]
+    Balanced,
+    Best,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeCurve {
+    /// Equal steps on the slider sound like equal steps in loudness.
+    #[default]
+    Log,
+    /// Like ALSA's mixer, finer control near the top of the slider.
+    Cubic,
+    /// Scales the amplitude directly, most of the slider's quiet end is inaudible.
+    Linear,
+    /// Ignores the slider, only the guild's `default_volume` applies.
+    Fixed,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecorderFormat {
+    /// 32-bit float, exactly the samples sent.
+    Wav,
+    /// Ogg Opus at songbird's bitrate, close to what listeners hear.
+    #[default]
+    Opus,
+}
+
+/// Where a player's stream is recorded, one file per track.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    pub format: RecorderFormat,
+}
+
+/// Settings of the audio path from librespot to songbird.
+pub struct AudioConfig {
+    pub target_latency: Duration,
+    /// Fade out when buffered audio is dropped and fade in after silence.
+    pub fade: Duration,
+    pub resampler: ResamplerEngine,
+    pub resampler_quality: ResamplerQuality,
+    /// Loudness targeting and limiting after resampling, `None` when disabled.
+    pub loudness: Option<LoudnessConfig>,
+    /// Overlap between consecutive tracks, zero when disabled.
+    pub crossfade: Duration,
+}
+
+/// Loudness the stream is adjusted towards and the peak level it may never exceed.
+#[derive(Clone, Copy)]
+pub struct LoudnessConfig {
+    pub target_lufs: f64,
+    pub ceiling_dbtp: f64,
+}
+
+/// Where librespot keeps its caches, a missing directory disables that cache.
+pub struct CachePaths {
+    pub credentials: Option<String>,
+    pub volume: Option<String>,
+    pub audio: Option<String>,
+    pub size_limit: u64,
+}
+
+pub struct ConfigKey;
+
+impl TypeMapKey for ConfigKey {
+    type Value = Config;
 }
 
 fn default_spotify_device_name() -> String {
     "Aoede".to_string()
 }
 
+fn default_spotify_bitrate() -> u16 {
+    320
+}
+
+fn default_spotify_gapless() -> bool {
+    true
+}
+
+fn default_spotify_normalisation_threshold_dbfs() -> f64 {
+    -2.0
+}
+
+fn default_spotify_normalisation_attack_ms() -> u64 {
+    5
+}
+
+fn default_spotify_normalisation_release_ms() -> u64 {
+    100
+}
+
+fn default_spotify_normalisation_knee_db() -> f64 {
+    5.0
+}
+
+fn default_cache_audio() -> bool {
+    true
+}
+
+fn default_cache_size_limit() -> u64 {
+    // 4 GB
+    4_000_000_000
+}
+
+fn default_audio_target_latency_ms() -> u64 {
+    100
+}
+
+fn default_recorder_dir() -> String {
+    "recordings".to_string()
+}
+
+fn default_audio_fade_ms() -> u64 {
+    20
+}
+
+fn default_audio_ceiling_dbtp() -> f64 {
+    -1.0
+}
+
+fn default_volume() -> f32 {
+    1.0
+}
+
+fn default_eq_q() -> f64 {
+    1.0
+}
+
+fn default_bass_frequency() -> f64 {
+    100.0
+}
+
+fn default_compressor_knee_db() -> f64 {
+    6.0
+}
+
+fn default_compressor_attack_ms() -> f64 {
+    10.0
+}
+
+fn default_compressor_release_ms() -> f64 {
+    150.0
+}
+
+fn default_follow() -> bool {
+    true
+}
+
+/// Accepts either a single value or a list, so that `DISCORD_USER_ID=123` keeps working.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(u64),
+        Many(Vec<u64>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(id) => vec![id],
+        OneOrMany::Many(ids) => ids,
+    })
+}
+
+/// Parses a setting with its `FromStr` implementation, like librespot's own option parsing.
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    let value = String::deserialize(deserializer)?;
+    value
+        .parse()
+        .map_err(|_| de::Error::custom(format!("invalid value '{}'", value)))
+}
+
+/// Accepts a number of bytes, or a human-readable size like `500MiB` or `1.5GB`.
+fn size<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(text) => {
+            parse_size(&text).ok_or_else(|| de::Error::custom(format!("invalid size '{}'", text)))
+        }
+    }
+}
+
+/// Parses a size with an optional decimal or binary unit, the `B` of which may be left out.
+/// Sizes that don't fit into a `u64` are rejected rather than clamped.
+fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        "ti" | "tib" => 1 << 40,
+        _ => return None,
+    };
+
+    let number: f64 = number.parse().ok()?;
+    let bytes = number * multiplier as f64;
+
+    // 2^64 is exactly representable, anything from there on doesn't fit
+    (bytes < u64::MAX as f64).then_some(bytes as u64)
+}
+
+/// Checks that `value` of the setting `name` lies within `range`.
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    name: &str,
+    value: T,
+    range: RangeInclusive<T>,
+) -> Result<(), String> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} must be between {} and {}, got {}",
+            name,
+            range.start(),
+            range.end(),
+            value
+        ))
+    }
+}
+
 impl Config {
+    /// The providers configuration is read from, in increasing order of precedence.
+    pub fn figment() -> Figment {
+        let figment = Figment::new()
+            .merge(Toml::file(CONFIG_PATH))
+            .merge(Env::raw());
+
+        merge_secret_files(figment, |variable| env::var_os(variable))
+    }
+
+    #[allow(clippy::result_large_err)]
     pub fn new() -> Result<Self, Error> {
-        let config: Config = Figment::new()
-            .merge(Toml::file("config.toml"))
-            .merge(Env::raw())
-            .extract()?;
+        let mut config: Config = Self::figment().extract()?;
+        config.read_profile_secrets()?;
+        config.validate(true)?;
         Ok(config)
     }
+
+    /// Reads the config without the settings only the Discord bot needs.
+    #[allow(clippy::result_large_err)]
+    pub fn headless() -> Result<Self, Error> {
+        let mut config: Config = Self::figment().extract()?;
+        config.read_profile_secrets()?;
+        config.validate(false)?;
+        Ok(config)
+    }
+
+    /// Replaces the credentials of profiles with the contents of their secret files.
+    #[allow(clippy::result_large_err)]
+    fn read_profile_secrets(&mut self) -> Result<(), Error> {
+        for profile in &mut self.profile {
+            let files = [
+                (
+                    "spotify_username_file",
+                    &profile.spotify_username_file,
+                    &mut profile.spotify_username,
+                ),
+                (
+                    "spotify_password_file",
+                    &profile.spotify_password_file,
+                    &mut profile.spotify_password,
+                ),
+            ];
+
+            for (key, path, value) in files {
+                if let Some(path) = path {
+                    let setting = format!("{} of profile '{}'", key, profile.name);
+                    *value = Some(read_secret(&setting, path)?);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn validate(&self, discord: bool) -> Result<(), Error> {
+        if discord && self.discord_token.is_empty() {
+            return Err(Kind::MissingField("discord_token".into()).into());
+        }
+
+        if self.profile.is_empty() {
+            // Without profiles, the top-level settings make up the only one
+            for (field, missing) in [
+                (
+                    "spotify_username",
+                    self.spotify_auth_mode == AuthMode::Password && self.spotify_username.is_none(),
+                ),
+                (
+                    "discord_user_id",
+                    discord && self.discord_user_ids.is_empty(),
+                ),
+            ] {
+                if missing {
+                    return Err(Kind::MissingField(field.into()).into());
+                }
+            }
+        }
+
+        for (i, profile) in self.profile.iter().enumerate() {
+            if profile.name.is_empty() {
+                return Err(format!("[[profile]] number {} has an empty name", i + 1).into());
+            }
+
+            let auth_mode = profile.spotify_auth_mode.unwrap_or(self.spotify_auth_mode);
+            if auth_mode == AuthMode::Password && profile.spotify_username.is_none() {
+                return Err(format!(
+                    "Profile '{}' needs a spotify_username to log in with a password",
+                    profile.name
+                )
+                .into());
+            }
+
+            if discord && profile.discord_user_ids.is_empty() {
+                return Err(format!("Profile '{}' needs a discord_user_id", profile.name).into());
+            }
+
+            if self.profile[..i]
+                .iter()
+                .any(|other| other.name == profile.name)
+            {
+                return Err(
+                    format!("Profile name '{}' is used more than once", profile.name).into(),
+                );
+            }
+        }
+
+        if ![96, 160, 320].contains(&self.spotify_bitrate) {
+            return Err(format!(
+                "spotify_bitrate must be 96, 160 or 320, got {}",
+                self.spotify_bitrate
+            )
+            .into());
+        }
+
+        // Same limits librespot applies to its command line options
+        check_range(
+            "spotify_normalisation_pregain_db",
+            self.spotify_normalisation_pregain_db,
+            -10.0..=10.0,
+        )?;
+        check_range(
+            "spotify_normalisation_threshold_dbfs",
+            self.spotify_normalisation_threshold_dbfs,
+            -10.0..=0.0,
+        )?;
+        check_range(
+            "spotify_normalisation_attack_ms",
+            self.spotify_normalisation_attack_ms,
+            1..=500,
+        )?;
+        check_range(
+            "spotify_normalisation_release_ms",
+            self.spotify_normalisation_release_ms,
+            1..=1000,
+        )?;
+        check_range(
+            "spotify_normalisation_knee_db",
+            self.spotify_normalisation_knee_db,
+            0.0..=10.0,
+        )?;
+
+        // Below two of songbird's 20 ms frames the buffer can't absorb any jitter
+        check_range(
+            "audio_target_latency_ms",
+            self.audio_target_latency_ms,
+            40..=2000,
+        )?;
+        // Fading out plays what is buffered, there is no more to fade
+        check_range(
+            "audio_fade_ms",
+            self.audio_fade_ms,
+            0..=self.audio_target_latency_ms,
+        )?;
+        if let Some(target) = self.audio_loudness_target_lufs {
+            check_range("audio_loudness_target_lufs", target, -36.0..=-5.0)?;
+        }
+        check_range("audio_ceiling_dbtp", self.audio_ceiling_dbtp, -9.0..=0.0)?;
+        // Spotify's own clients offer up to 12 seconds
+        check_range("audio_crossfade_ms", self.audio_crossfade_ms, 0..=12000)?;
+
+        for (guild_id, guild) in &self.guild {
+            if guild_id.parse::<u64>().is_err() {
+                return Err(format!("[guild.{}] is not a guild ID", guild_id).into());
+            }
+
+            check_range(
+                &format!("[guild.{}] default_volume", guild_id),
+                guild.default_volume,
+                0.0..=2.0,
+            )?;
+
+            for effect in &guild.effects {
+                effect.validate(&format!("[[guild.{}.effects]]", guild_id))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configured profiles, or a single one made from the top-level settings.
+    pub fn profiles(&self) -> Vec<Profile> {
+        if self.profile.is_empty() {
+            return vec![Profile {
+                name: DEFAULT_PROFILE.to_string(),
+                spotify_auth_mode: self.spotify_auth_mode,
+                spotify_username: self.spotify_username.clone(),
+                spotify_password: self.spotify_password.clone(),
+                discord_user_ids: self.discord_user_ids.clone(),
+                broadcast_channel_ids: self.broadcast_channel_ids.clone(),
+                spotify_device_name: self.spotify_device_name.clone(),
+                spotify_bot_autoplay: self.spotify_bot_autoplay,
+            }];
+        }
+
+        self.profile
+            .iter()
+            .map(|profile| Profile {
+                name: profile.name.clone(),
+                spotify_auth_mode: profile.spotify_auth_mode.unwrap_or(self.spotify_auth_mode),
+                spotify_username: profile.spotify_username.clone(),
+                spotify_password: profile.spotify_password.clone(),
+                discord_user_ids: profile.discord_user_ids.clone(),
+                broadcast_channel_ids: profile.broadcast_channel_ids.clone(),
+                spotify_device_name: profile
+                    .spotify_device_name
+                    .clone()
+                    .unwrap_or_else(|| self.spotify_device_name.clone()),
+                spotify_bot_autoplay: profile
+                    .spotify_bot_autoplay
+                    .unwrap_or(self.spotify_bot_autoplay),
+            })
+            .collect()
+    }
+
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        self.profiles()
+            .into_iter()
+            .find(|profile| profile.name == name)
+    }
+
+    /// Cache directories of a profile, each falling back to `cache_dir` when not set. With
+    /// several profiles, credentials and volume go into a subdirectory named after the profile,
+    /// while the audio cache is shared.
+    pub fn cache_paths(&self, profile: &Profile) -> CachePaths {
+        let or_cache_dir = |dir: &Option<String>| dir.clone().or_else(|| self.cache_dir.clone());
+        let per_profile = |dir: Option<String>| {
+            if self.profile.is_empty() {
+                dir
+            } else {
+                dir.map(|dir| {
+                    Path::new(&dir)
+                        .join(&profile.name)
+                        .to_string_lossy()
+                        .into_owned()
+                })
+            }
+        };
+
+        CachePaths {
+            credentials: per_profile(or_cache_dir(&self.cache_credentials_dir)),
+            volume: per_profile(or_cache_dir(&self.cache_volume_dir)),
+            audio: if self.cache_audio {
+                or_cache_dir(&self.cache_audio_dir)
+            } else {
+                None
+            },
+            size_limit: self.cache_size_limit,
+        }
+    }
+
+    /// Where to record the given profile's stream. With several profiles, each records into a
+    /// subdirectory named after the profile.
+    pub fn recorder_config(&self, profile_name: &str) -> RecorderConfig {
+        let mut dir = PathBuf::from(&self.recorder_dir);
+        if !self.profile.is_empty() {
+            dir.push(profile_name);
+        }
+
+        RecorderConfig {
+            dir,
+            format: self.recorder_format,
+        }
+    }
+
+    /// The recorder settings to apply, `None` unless recording is enabled.
+    pub fn recording(&self, profile_name: &str) -> Option<RecorderConfig> {
+        self.recorder_enabled
+            .then(|| self.recorder_config(profile_name))
+    }
+
+    /// The librespot player configuration described by these settings.
+    pub fn player_config(&self) -> PlayerConfig {
+        let bitrate = match self.spotify_bitrate {
+            96 => Bitrate::Bitrate96,
+            160 => Bitrate::Bitrate160,
+            _ => Bitrate::Bitrate320,
+        };
+
+        PlayerConfig {
+            bitrate,
+            gapless: self.spotify_gapless,
+            normalisation: self.spotify_normalisation,
+            normalisation_type: self.spotify_normalisation_type,
+            normalisation_method: self.spotify_normalisation_method,
+            normalisation_pregain_db: self.spotify_normalisation_pregain_db,
+            normalisation_threshold_dbfs: self.spotify_normalisation_threshold_dbfs,
+            normalisation_attack_cf: duration_to_coefficient(Duration::from_millis(
+                self.spotify_normalisation_attack_ms,
+            )),
+            normalisation_release_cf: duration_to_coefficient(Duration::from_millis(
+                self.spotify_normalisation_release_ms,
+            )),
+            normalisation_knee_db: self.spotify_normalisation_knee_db,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the players built from `new` would play differently. `PlayerConfig` can't be
+    /// compared as a whole, so every field except the ditherer is compared.
+    pub fn player_config_changed(&self, new: &Config) -> bool {
+        let (old, new) = (self.player_config(), new.player_config());
+
+        old.bitrate != new.bitrate
+            || old.gapless != new.gapless
+            || old.passthrough != new.passthrough
+            || old.normalisation != new.normalisation
+            || old.normalisation_type != new.normalisation_type
+            || old.normalisation_method != new.normalisation_method
+            || old.normalisation_pregain_db != new.normalisation_pregain_db
+            || old.normalisation_threshold_dbfs != new.normalisation_threshold_dbfs
+            || old.normalisation_attack_cf != new.normalisation_attack_cf
+            || old.normalisation_release_cf != new.normalisation_release_cf
+            || old.normalisation_knee_db != new.normalisation_knee_db
+    }
+
+    /// Configuration of the mixer that applies the Spotify volume.
+    pub fn mixer_config(&self) -> MixerConfig {
+        let volume_ctrl = match self.spotify_volume_curve {
+            VolumeCurve::Log => VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE),
+            VolumeCurve::Cubic => VolumeCtrl::Cubic(VolumeCtrl::DEFAULT_DB_RANGE),
+            VolumeCurve::Linear => VolumeCtrl::Linear,
+            VolumeCurve::Fixed => VolumeCtrl::Fixed,
+        };
+
+        MixerConfig {
+            volume_ctrl,
+            ..MixerConfig::default()
+        }
+    }
+
+    pub fn audio_config(&self) -> AudioConfig {
+        AudioConfig {
+            target_latency: Duration::from_millis(self.audio_target_latency_ms),
+            fade: Duration::from_millis(self.audio_fade_ms),
+            resampler: self.audio_resampler,
+            resampler_quality: self.audio_resampler_quality,
+            loudness: self
+                .audio_loudness_target_lufs
+                .map(|target_lufs| LoudnessConfig {
+                    target_lufs,
+                    ceiling_dbtp: self.audio_ceiling_dbtp,
+                }),
+            crossfade: Duration::from_millis(self.audio_crossfade_ms),
+        }
+    }
+
+    /// Settings for the given guild, falling back to the defaults if it has no section.
+    pub fn guild(&self, guild_id: u64) -> GuildConfig {
+        self.guild
+            .get(&guild_id.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl EffectConfig {
+    #[allow(clippy::result_large_err)]
+    fn validate(&self, table: &str) -> Result<(), Error> {
+        let field = |name: &str| format!("{} {}", table, name);
+
+        match *self {
+            EffectConfig::Eq {
+                frequency,
+                gain_db,
+                q,
+            } => {
+                check_range(&field("frequency"), frequency, 20.0..=20000.0)?;
+                check_range(&field("gain_db"), gain_db, -24.0..=24.0)?;
+                check_range(&field("q"), q, 0.1..=10.0)?;
+            }
+            EffectConfig::Bass { frequency, gain_db } => {
+                check_range(&field("frequency"), frequency, 20.0..=500.0)?;
+                check_range(&field("gain_db"), gain_db, -24.0..=24.0)?;
+            }
+            EffectConfig::Compressor {
+                threshold_db,
+                ratio,
+                knee_db,
+                attack_ms,
+                release_ms,
+                makeup_db,
+            } => {
+                check_range(&field("threshold_db"), threshold_db, -60.0..=0.0)?;
+                check_range(&field("ratio"), ratio, 1.0..=20.0)?;
+                check_range(&field("knee_db"), knee_db, 0.0..=24.0)?;
+                check_range(&field("attack_ms"), attack_ms, 0.1..=500.0)?;
+                check_range(&field("release_ms"), release_ms, 1.0..=5000.0)?;
+                check_range(&field("makeup_db"), makeup_db, 0.0..=24.0)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Merges the secret files named by the `*_FILE` variables `var` looks up, so they take
+/// precedence over the setting itself.
+fn merge_secret_files(
+    mut figment: Figment,
+    var: impl Fn(&str) -> Option<std::ffi::OsString>,
+) -> Figment {
+    for key in SECRET_FILE_SETTINGS {
+        let variable = format!("{}_FILE", key.to_uppercase());
+
+        if let Some(path) = var(&variable) {
+            figment = figment.merge(SecretFile {
+                key,
+                variable,
+                path: path.into(),
+            });
+        }
+    }
+
+    figment
+}
+
+/// Provides a single setting from a mounted secret file, as used by Docker and Kubernetes
+/// secrets. Surrounding whitespace is trimmed, and missing or empty files are an error.
+struct SecretFile {
+    key: &'static str,
+    variable: String,
+    path: PathBuf,
+}
+
+impl Provider for SecretFile {
+    fn metadata(&self) -> Metadata {
+        Metadata::from("secret file", self.path.as_path())
+    }
+
+    fn data(&self) -> Result<Map<figment::Profile, Dict>, Error> {
+        let secret = read_secret(&self.variable, &self.path)?;
+
+        let mut dict = Dict::new();
+        dict.insert(self.key.to_string(), secret.into());
+
+        Ok(figment::Profile::Default.collect(dict))
+    }
+}
+
+/// Reads a secret from the file at `path`, which `setting` points at, without surrounding
+/// whitespace.
+#[allow(clippy::result_large_err)]
+fn read_secret(setting: &str, path: &Path) -> Result<String, Error> {
+    let contents = fs::read_to_string(path).map_err(|why| {
+        format!(
+            "{} is set to '{}', which could not be read: {}",
+            setting,
+            path.display(),
+            why
+        )
+    })?;
+
+    let secret = contents.trim();
+    if secret.is_empty() {
+        return Err(format!("{} is set to '{}', which is empty", setting, path.display()).into());
+    }
+
+    Ok(secret.to_string())
+}
+
+/// A setting that differs between two loaded configurations.
+pub struct ConfigChange {
+    pub field: &'static str,
+    /// Whether the new value only takes effect after Aoede is restarted.
+    pub requires_restart: bool,
+}
+
+/// Pairs every field of two configs with whether it changed and whether that requires a restart.
+/// Leaving a field of [`Config`] out doesn't compile, so new settings can't be forgotten here.
+macro_rules! compare {
+    ($old:expr, $new:expr, { $($field:ident: $requires_restart:expr),* $(,)? }) => {{
+        let Config { $($field: _),* } = $new;
+        [$((stringify!($field), $old.$field != $new.$field, $requires_restart)),*]
+    }};
+}
+
+impl Config {
+    /// Lists the settings that changed from `self` to `new`.
+    pub fn changes(&self, new: &Config) -> Vec<ConfigChange> {
+        let fields = compare!(self, new, {
+            discord_token: true,
+            spotify_auth_mode: true,
+            spotify_username: true,
+            spotify_password: true,
+            discord_user_ids: false,
+            broadcast_channel_ids: false,
+            spotify_bot_autoplay: false,
+            spotify_device_name: false,
+            guild: false,
+            profile: self.profile.len() != new.profile.len()
+                || self.profile.iter().zip(&new.profile).any(|(old, new)| {
+                    old.name != new.name
+                        || old.spotify_auth_mode != new.spotify_auth_mode
+                        || old.spotify_username != new.spotify_username
+                        || old.spotify_password != new.spotify_password
+                }),
+            spotify_bitrate: false,
+            spotify_gapless: false,
+            spotify_normalisation: false,
+            spotify_normalisation_type: false,
+            spotify_normalisation_method: false,
+            spotify_normalisation_pregain_db: false,
+            spotify_normalisation_threshold_dbfs: false,
+            spotify_normalisation_attack_ms: false,
+            spotify_normalisation_release_ms: false,
+            spotify_normalisation_knee_db: false,
+            spotify_volume_curve: true,
+            cache_dir: true,
+            cache_credentials_dir: true,
+            cache_volume_dir: true,
+            cache_audio_dir: true,
+            cache_audio: true,
+            cache_size_limit: true,
+            audio_target_latency_ms: true,
+            audio_fade_ms: true,
+            audio_resampler: true,
+            audio_resampler_quality: true,
+            audio_loudness_target_lufs: true,
+            audio_ceiling_dbtp: true,
+            audio_crossfade_ms: true,
+            recorder_enabled: false,
+            recorder_dir: false,
+            recorder_format: false,
+        });
+
+        fields
+            .iter()
+            .filter(|(_, changed, _)| *changed)
+            .map(|&(field, _, requires_restart)| ConfigChange {
+                field,
+                requires_restart,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Config {
+        Figment::from(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[test]
+    fn profile_secrets_are_read_from_files() {
+        let dir = env::temp_dir().join(format!("aoede-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("password"), "  hunter2\n").unwrap();
+        fs::write(dir.join("empty"), "\n").unwrap();
+
+        let profile = |file: &str| {
+            format!(
+                "spotify_bot_autoplay = true\n\
+                [[profile]]\n\
+                name = \"alice\"\n\
+                spotify_username = \"alice\"\n\
+                spotify_password = \"plain\"\n\
+                spotify_password_file = {:?}\n",
+                dir.join(file)
+            )
+        };
+
+        let mut config = parse(&profile("password"));
+        config.read_profile_secrets().unwrap();
+        assert_eq!(
+            config.profiles()[0].spotify_password.as_deref(),
+            Some("hunter2")
+        );
+
+        let error = parse(&profile("empty")).read_profile_secrets().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("spotify_password_file of profile 'alice'"));
+        assert!(error.to_string().contains("empty"));
+
+        let error = parse(&profile("missing"))
+            .read_profile_secrets()
+            .unwrap_err();
+        assert!(error.to_string().contains("could not be read"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn secret_files_are_read() {
+        let dir = env::temp_dir().join(format!("aoede-secret-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("token"), "file-token\n").unwrap();
+        fs::write(dir.join("password"), "hunter2\r\n").unwrap();
+
+        let extract = |toml: &str, files: &[(&str, &str)]| {
+            let figment = Figment::from(Toml::string(toml));
+            merge_secret_files(figment, |variable| {
+                files
+                    .iter()
+                    .find(|(name, _)| *name == variable)
+                    .map(|(_, file)| dir.join(file).into_os_string())
+            })
+            .extract::<Config>()
+            .map_err(|why| why.to_string())
+        };
+
+        let config = extract(
+            "spotify_bot_autoplay = false\n\
+            discord_token = \"inline-token\"",
+            &[
+                ("DISCORD_TOKEN_FILE", "token"),
+                ("SPOTIFY_PASSWORD_FILE", "password"),
+            ],
+        )
+        .unwrap();
+        // The file wins over the setting itself, without the trailing newline
+        assert_eq!(config.discord_token, "file-token");
+        assert_eq!(config.spotify_password.as_deref(), Some("hunter2"));
+
+        let error = extract(
+            "spotify_bot_autoplay = false",
+            &[("SPOTIFY_USERNAME_FILE", "missing")],
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("SPOTIFY_USERNAME_FILE"));
+        assert!(error.contains("could not be read"));
+
+        let error = extract(
+            "spotify_bot_autoplay = false",
+            &[("DISCORD_TOKEN_FILE", "")],
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.contains("DISCORD_TOKEN_FILE"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+        let cases: &[(&str, Option<u64>)] = &[
+            ("0", Some(0)),
+            ("123", Some(123)),
+            ("123B", Some(123)),
+            ("  42 b ", Some(42)),
+            ("1K", Some(1_000)),
+            ("1kb", Some(1_000)),
+            ("1.5M", Some(1_500_000)),
+            ("1.5 MB", Some(1_500_000)),
+            ("4G", Some(4_000_000_000)),
+            ("4GB", Some(4_000_000_000)),
+            ("2T", Some(2_000_000_000_000)),
+            ("1KiB", Some(1 << 10)),
+            ("500Mi", Some(500 << 20)),
+            ("500MiB", Some(500 << 20)),
+            ("1.5gib", Some(3 << 29)),
+            ("18446744073709551615", None),
+            ("20000000TB", None),
+            ("", None),
+            ("GB", None),
+            ("-1GB", None),
+            ("1.2.3MB", None),
+            ("12 apples", None),
+            ("1PB", None),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(parse_size(text), *expected, "{:?}", text);
+        }
+    }
+
+    /// Parses the settings after the one every config needs.
+    fn config(toml: &str) -> Config {
+        parse(&format!("spotify_bot_autoplay = false\n{}", toml))
+    }
+
+    fn changed(old: &str, new: &str) -> Vec<(&'static str, bool)> {
+        config(old)
+            .changes(&config(new))
+            .into_iter()
+            .map(|change| (change.field, change.requires_restart))
+            .collect()
+    }
+
+    #[test]
+    fn changes_are_classified() {
+        assert!(changed("guild = {}", "guild = {}").is_empty());
+
+        assert_eq!(
+            changed("discord_token = \"a\"", "discord_token = \"b\""),
+            [("discord_token", true)]
+        );
+        assert_eq!(
+            changed("", "discord_user_id = 1\nrecorder_dir = \"out\""),
+            [("discord_user_ids", false), ("recorder_dir", false)]
+        );
+        assert_eq!(
+            changed("", "spotify_volume_curve = \"linear\""),
+            [("spotify_volume_curve", true)]
+        );
+        assert_eq!(
+            changed("", "spotify_normalisation_knee_db = 3.0"),
+            [("spotify_normalisation_knee_db", false)]
+        );
+        assert_eq!(
+            changed("", "[guild.1]\ndefault_volume = 0.5"),
+            [("guild", false)]
+        );
+    }
+
+    #[test]
+    fn profile_changes_restart_only_for_credentials() {
+        let profile = |user: &str, followed: u64| {
+            format!(
+                "[[profile]]\n\
+                name = \"alice\"\n\
+                spotify_username = {:?}\n\
+                discord_user_id = {}\n",
+                user, followed
+            )
+        };
+
+        assert_eq!(
+            changed(&profile("alice", 1), &profile("alice", 2)),
+            [("profile", false)]
+        );
+        assert_eq!(
+            changed(&profile("alice", 1), &profile("bob", 1)),
+            [("profile", true)]
+        );
+        assert_eq!(
+            changed(
+                &profile("alice", 1),
+                &(profile("alice", 1) + &profile("bob", 1))
+            ),
+            [("profile", true)]
+        );
+    }
+
+    #[test]
+    fn player_config_changes_only_with_playback_settings() {
+        let changes = |old: &str, new: &str| config(old).player_config_changed(&config(new));
+
+        assert!(!changes("", ""));
+        assert!(!changes("", "spotify_device_name = \"Den\""));
+        assert!(!parse("spotify_bot_autoplay = false")
+            .player_config_changed(&parse("spotify_bot_autoplay = true")));
+        assert!(!changes("spotify_bitrate = 320", "spotify_bitrate = 999"));
+
+        assert!(changes("", "spotify_bitrate = 96"));
+        assert!(changes("", "spotify_normalisation = true"));
+        assert!(changes("", "spotify_normalisation_attack_ms = 1"));
+    }
 }
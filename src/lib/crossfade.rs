@@ -0,0 +1,366 @@
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::PlayerEvent;
+
+use songbird::constants::SAMPLE_RATE_RAW;
+
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::mem;
+use std::time::Duration;
+
+/// Whether `next` comes right after `current` in `tracks`, the tracks of an album. Tracks that
+/// continue each other on an album are cut to rather than overlapped.
+pub fn follows_on(tracks: &[SpotifyId], current: SpotifyId, next: SpotifyId) -> bool {
+    tracks.windows(2).any(|pair| pair == [current, next])
+}
+
+/// Overlaps the end of a track that plays to its end with the start of the next one.
+///
+/// While a track nears its end its last samples are held back, and once the next track starts
+/// they are faded out under its head.
+pub struct Crossfade {
+    /// Samples to overlap, zero when disabled.
+    length: usize,
+    /// Samples left of the current track, from its duration and the position last reported.
+    remaining: Option<i64>,
+    end_of_track: bool,
+    /// Next track to cut to without overlap, because it continues the current one.
+    cut_to: Option<SpotifyId>,
+    /// Held back end of the current track, or of the outgoing one while fading.
+    tail: VecDeque<f32>,
+    fade: Option<Fade>,
+    output: Vec<f32>,
+}
+
+struct Fade {
+    /// Samples of the outgoing track still to come out of the resampler.
+    outgoing: usize,
+    /// Frames in the overlap, known once all of the outgoing track is held back.
+    frames: Option<usize>,
+    done: usize,
+}
+
+impl Crossfade {
+    pub fn new(length: Duration) -> Crossfade {
+        let length = (length.as_secs_f64() * SAMPLE_RATE_RAW as f64) as usize * 2;
+
+        Crossfade {
+            length,
+            remaining: None,
+            end_of_track: false,
+            cut_to: None,
+            tail: VecDeque::with_capacity(length),
+            fade: None,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.length > 0
+    }
+
+    /// Plays `track_id` without overlap if it comes next.
+    pub fn cut_to(&mut self, track_id: SpotifyId) {
+        self.cut_to = Some(track_id);
+    }
+
+    /// Frames held back, which come out before anything written after them.
+    pub fn held(&self) -> usize {
+        self.tail.len() / 2
+    }
+
+    /// Follows the player's events, in step with the audio. `pending` is how many frames of the
+    /// packets written so far are still inside the resampler.
+    pub fn event(&mut self, event: &PlayerEvent, pending: usize) {
+        if self.length == 0 {
+            return;
+        }
+
+        match *event {
+            PlayerEvent::Playing {
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                let remaining_ms = duration_ms as i64 - position_ms as i64;
+                self.remaining = Some(remaining_ms * SAMPLE_RATE_RAW as i64 / 1000 * 2);
+            }
+            PlayerEvent::EndOfTrack { .. } => self.end_of_track = true,
+            PlayerEvent::Changed { new_track_id, .. } => {
+                let cut = self.cut_to.take() == Some(new_track_id);
+                self.remaining = None;
+
+                if !mem::take(&mut self.end_of_track) {
+                    // Skipped, the sink is about to be flushed
+                    self.reset();
+                } else if !cut && !self.tail.is_empty() {
+                    self.fade = Some(Fade {
+                        outgoing: pending * 2,
+                        frames: None,
+                        done: 0,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Takes resampled samples and returns those ready to be played.
+    pub fn process<'a>(&'a mut self, mut samples: &'a [f32]) -> &'a [f32] {
+        if self.length == 0 {
+            return samples;
+        }
+
+        self.output.clear();
+
+        if let Some(fade) = &mut self.fade {
+            let outgoing = fade.outgoing.min(samples.len());
+            fade.outgoing -= outgoing;
+            self.tail.extend(&samples[..outgoing]);
+            samples = &samples[outgoing..];
+
+            let excess = self.tail.len().saturating_sub(self.length);
+            self.output.extend(self.tail.drain(..excess));
+        }
+
+        self.remaining = self
+            .remaining
+            .map(|remaining| remaining - samples.len() as i64);
+
+        if let Some(fade) = self.fade.as_mut().filter(|fade| fade.outgoing == 0) {
+            let frames = *fade.frames.get_or_insert(self.tail.len() / 2);
+            let overlap = samples.len().min(self.tail.len());
+
+            // Equal power, so the loudness doesn't dip halfway through
+            for frame in samples[..overlap].chunks_exact(2) {
+                let progress = (fade.done as f32 + 0.5) / frames as f32;
+                let (fade_in, fade_out) = (progress * FRAC_PI_2).sin_cos();
+
+                for sample in frame {
+                    let tail = self.tail.pop_front().unwrap();
+                    self.output.push(tail * fade_out + sample * fade_in);
+                }
+
+                fade.done += 1;
+            }
+
+            samples = &samples[overlap..];
+            if self.tail.is_empty() {
+                self.fade = None;
+            }
+        }
+
+        if self.fade.is_some() {
+            return &self.output;
+        }
+
+        // Hold back what is left of the last `length` samples of the track
+        let held = match self.remaining {
+            _ if self.end_of_track => self.length,
+            Some(remaining) => {
+                (self.length as i64 - remaining).clamp(0, self.length as i64) as usize
+            }
+            None => 0,
+        };
+
+        if self.output.is_empty() && self.tail.is_empty() && held == 0 {
+            return samples;
+        }
+
+        self.tail.extend(samples);
+        let excess = self.tail.len().saturating_sub(held);
+        self.output.extend(self.tail.drain(..excess));
+
+        &self.output
+    }
+
+    /// Gives out the samples held back, as they are.
+    pub fn drain(&mut self) -> &[f32] {
+        self.fade = None;
+        self.output.clear();
+        self.output.extend(self.tail.drain(..));
+        &self.output
+    }
+
+    /// Drops the samples held back. What was learnt from the events is kept, they are already
+    /// ahead of the audio.
+    pub fn reset(&mut self) {
+        self.tail.clear();
+        self.fade = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 100 ms of overlap.
+    const LENGTH_MS: u64 = 100;
+    const LENGTH: usize = SAMPLE_RATE_RAW / 10 * 2;
+
+    fn track(id: &str) -> SpotifyId {
+        SpotifyId::from_base62(id).unwrap()
+    }
+
+    fn first() -> SpotifyId {
+        track("4uLU6hMCjMI75M1A2tKUQC")
+    }
+
+    fn second() -> SpotifyId {
+        track("6rqhFgbbKwnb9MLmUQDhG6")
+    }
+
+    fn playing(track_id: SpotifyId, duration_ms: u32) -> PlayerEvent {
+        PlayerEvent::Playing {
+            track_id,
+            play_request_id: 0,
+            position_ms: 0,
+            duration_ms,
+        }
+    }
+
+    /// Plays a second of `outgoing` to its end, or skips it half way, followed by a second of
+    /// `incoming`. Returns everything given out, and the frames held back before the change.
+    fn play(
+        crossfade: &mut Crossfade,
+        outgoing: f32,
+        incoming: f32,
+        skip: bool,
+    ) -> (Vec<f32>, usize) {
+        let mut output = Vec::new();
+        let track = vec![outgoing; SAMPLE_RATE_RAW * 2];
+        let played = if skip { track.len() / 2 } else { track.len() };
+
+        crossfade.event(&playing(first(), 1_000), 0);
+        // In packets, like the resampler gives them out
+        for packet in track[..played].chunks(960) {
+            output.extend_from_slice(crossfade.process(packet));
+        }
+        let held = crossfade.held();
+
+        if !skip {
+            crossfade.event(
+                &PlayerEvent::EndOfTrack {
+                    play_request_id: 0,
+                    track_id: first(),
+                },
+                0,
+            );
+        }
+        crossfade.event(
+            &PlayerEvent::Changed {
+                old_track_id: first(),
+                new_track_id: second(),
+            },
+            0,
+        );
+        crossfade.event(&playing(second(), 1_000), 0);
+
+        for packet in vec![incoming; SAMPLE_RATE_RAW * 2].chunks(960) {
+            output.extend_from_slice(crossfade.process(packet));
+        }
+        output.extend_from_slice(crossfade.drain());
+
+        (output, held)
+    }
+
+    #[test]
+    fn holds_back_the_end_of_the_track() {
+        let mut crossfade = Crossfade::new(Duration::from_millis(LENGTH_MS));
+        let (output, held) = play(&mut crossfade, 0.5, 0.0, false);
+
+        assert_eq!(held, LENGTH / 2);
+        // The held back end is played under the next track, which is as long again
+        assert_eq!(output.len(), SAMPLE_RATE_RAW * 4 - LENGTH);
+    }
+
+    #[test]
+    fn overlap_keeps_the_power_constant() {
+        // The fades on their own, each track silent while the other plays
+        let (fade_out, _) = play(
+            &mut Crossfade::new(Duration::from_millis(LENGTH_MS)),
+            1.0,
+            0.0,
+            false,
+        );
+        let (fade_in, _) = play(
+            &mut Crossfade::new(Duration::from_millis(LENGTH_MS)),
+            0.0,
+            1.0,
+            false,
+        );
+
+        let start = SAMPLE_RATE_RAW * 2 - LENGTH;
+        let overlap = start..start + LENGTH;
+        for (out, into) in fade_out[overlap.clone()].iter().zip(&fade_in[overlap]) {
+            assert!((out * out + into * into - 1.0).abs() < 1e-4);
+        }
+
+        // The outgoing track fades all the way out, the incoming one all the way in
+        assert!(fade_out[start] > 0.99 && fade_out[start + LENGTH - 1] < 0.02);
+        assert!(fade_in[start] < 0.02 && fade_in[start + LENGTH - 1] > 0.99);
+        assert!(fade_out[..start].iter().all(|sample| *sample == 1.0));
+        assert!(fade_in[start + LENGTH..]
+            .iter()
+            .all(|sample| *sample == 1.0));
+    }
+
+    #[test]
+    fn cut_to_leaves_the_transition_alone() {
+        let mut crossfade = Crossfade::new(Duration::from_millis(LENGTH_MS));
+        crossfade.cut_to(second());
+        let (output, _) = play(&mut crossfade, 0.25, 0.5, false);
+
+        assert_eq!(output.len(), SAMPLE_RATE_RAW * 4);
+        assert!(output[..SAMPLE_RATE_RAW * 2]
+            .iter()
+            .all(|sample| *sample == 0.25));
+        assert!(output[SAMPLE_RATE_RAW * 2..]
+            .iter()
+            .all(|sample| *sample == 0.5));
+    }
+
+    #[test]
+    fn skipping_drops_what_was_held_back() {
+        let mut crossfade = Crossfade::new(Duration::from_millis(LENGTH_MS));
+        // Skipped half way, before anything is held back
+        let (output, held) = play(&mut crossfade, 0.25, 0.5, true);
+
+        assert_eq!(held, 0);
+        assert_eq!(output.len(), SAMPLE_RATE_RAW * 3);
+        assert!(output[SAMPLE_RATE_RAW..]
+            .iter()
+            .all(|sample| *sample == 0.5));
+    }
+
+    #[test]
+    fn skipping_late_in_the_track_drops_the_tail() {
+        let mut crossfade = Crossfade::new(Duration::from_millis(LENGTH_MS));
+        crossfade.event(&playing(first(), 1_000), 0);
+        crossfade.process(&vec![0.25; SAMPLE_RATE_RAW * 2 - LENGTH / 2]);
+        assert_eq!(crossfade.held(), LENGTH / 4);
+
+        crossfade.event(
+            &PlayerEvent::Changed {
+                old_track_id: first(),
+                new_track_id: second(),
+            },
+            0,
+        );
+        assert_eq!(crossfade.held(), 0);
+        assert_eq!(crossfade.process(&[0.5; 960]), [0.5; 960]);
+    }
+
+    #[test]
+    fn album_order() {
+        let third = track("2takcwOaAZWiXQijPHIx7B");
+        let album = [first(), second(), third];
+
+        assert!(follows_on(&album, first(), second()));
+        assert!(follows_on(&album, second(), third));
+        assert!(!follows_on(&album, first(), third));
+        assert!(!follows_on(&album, second(), first()));
+        assert!(!follows_on(&album, third, first()));
+        assert!(!follows_on(&[], first(), second()));
+    }
+}
@@ -0,0 +1,468 @@
+use std::f64::consts::PI;
+
+use songbird::constants::SAMPLE_RATE_RAW;
+
+use super::config::{EffectConfig, LoudnessConfig};
+use super::loudness::LoudnessLimiter;
+
+/// A stage the sink runs on the resampled 48 kHz stream.
+pub trait AudioProcessor: Send {
+    /// Processes a block of interleaved stereo samples in place.
+    fn process(&mut self, samples: &mut [f32]);
+
+    /// Appends the audio still held back at the end of a stream, for processors that delay
+    /// their output.
+    fn drain(&mut self, _samples: &mut Vec<f32>) {}
+
+    /// Frames the output lags behind the input.
+    fn delay(&self) -> usize {
+        0
+    }
+
+    /// Forgets the audio processed so far, what comes next is unrelated to it.
+    fn reset(&mut self);
+}
+
+/// The processors of one reader of a sink: its guild's effects, followed by the loudness limiter
+/// so that it catches whatever the effects push above the ceiling.
+pub struct Chain {
+    processors: Vec<Box<dyn AudioProcessor>>,
+    /// Configuration of the effects at the start of `processors`.
+    effects: Vec<EffectConfig>,
+    /// Copy of the block being processed, the resampler's output is read-only.
+    block: Vec<f32>,
+}
+
+impl Chain {
+    pub fn new(loudness: Option<LoudnessConfig>) -> Chain {
+        let mut processors: Vec<Box<dyn AudioProcessor>> = Vec::new();
+        if let Some(config) = loudness {
+            processors.push(Box::new(LoudnessLimiter::new(config)));
+        }
+
+        Chain {
+            processors,
+            effects: Vec::new(),
+            block: Vec::new(),
+        }
+    }
+
+    /// Replaces the effects, unless they are configured the same way already and would only
+    /// lose their state.
+    pub fn set_effects(&mut self, effects: &[EffectConfig]) {
+        if self.effects == effects {
+            return;
+        }
+
+        self.processors
+            .splice(..self.effects.len(), effects.iter().map(effect));
+        self.effects = effects.to_vec();
+    }
+
+    /// Runs every processor over `samples`. Without any, the samples are returned as they are.
+    pub fn process<'a>(&'a mut self, samples: &'a [f32]) -> &'a [f32] {
+        if self.processors.is_empty() {
+            return samples;
+        }
+
+        self.block.clear();
+        self.block.extend_from_slice(samples);
+
+        for processor in &mut self.processors {
+            processor.process(&mut self.block);
+        }
+
+        &self.block
+    }
+
+    /// Collects the audio the processors still hold, each processor's tail running through the
+    /// ones after it.
+    pub fn drain(&mut self) -> &[f32] {
+        self.block.clear();
+
+        for i in 0..self.processors.len() {
+            let start = self.block.len();
+            self.processors[i].drain(&mut self.block);
+
+            for processor in &mut self.processors[i + 1..] {
+                processor.process(&mut self.block[start..]);
+            }
+        }
+
+        &self.block
+    }
+
+    /// Frames the output lags behind the input.
+    pub fn delay(&self) -> usize {
+        self.processors
+            .iter()
+            .map(|processor| processor.delay())
+            .sum()
+    }
+
+    pub fn reset(&mut self) {
+        for processor in &mut self.processors {
+            processor.reset();
+        }
+    }
+}
+
+fn effect(config: &EffectConfig) -> Box<dyn AudioProcessor> {
+    match *config {
+        EffectConfig::Eq {
+            frequency,
+            gain_db,
+            q,
+        } => Box::new(Filter::new(Biquad::peaking(frequency, gain_db, q))),
+        EffectConfig::Bass { frequency, gain_db } => {
+            Box::new(Filter::new(Biquad::low_shelf(frequency, gain_db)))
+        }
+        EffectConfig::Compressor {
+            threshold_db,
+            ratio,
+            knee_db,
+            attack_ms,
+            release_ms,
+            makeup_db,
+        } => Box::new(Compressor {
+            threshold_db,
+            ratio,
+            knee_db,
+            attack: smoothing_coefficient(attack_ms),
+            release: smoothing_coefficient(release_ms),
+            makeup_db,
+            reduction_db: 0.0,
+        }),
+    }
+}
+
+/// Biquad filter in direct form I, with the coefficients normalized so that `a0` is 1.
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    /// Last two inputs and outputs.
+    state: [f64; 4],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            state: [0.0; 4],
+        }
+    }
+
+    /// Boosts or cuts around `frequency`, the higher `q` the narrower. From the Audio EQ
+    /// Cookbook, like the low shelf.
+    fn peaking(frequency: f64, gain_db: f64, q: f64) -> Biquad {
+        let a = 10f64.powf(gain_db / 40.0);
+        let (sin, cos) = angular_frequency(frequency).sin_cos();
+        let alpha = sin / (2.0 * q);
+
+        Biquad::normalized(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    /// Boosts or cuts everything below `frequency`, with the steepest slope that doesn't
+    /// overshoot.
+    fn low_shelf(frequency: f64, gain_db: f64) -> Biquad {
+        let a = 10f64.powf(gain_db / 40.0);
+        let (sin, cos) = angular_frequency(frequency).sin_cos();
+        let alpha = sin / 2.0 * 2f64.sqrt();
+        let root = 2.0 * a.sqrt() * alpha;
+
+        Biquad::normalized(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + root),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + root,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad::new(
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [a[1] / a[0], a[2] / a[0]],
+        )
+    }
+
+    pub fn process(&mut self, input: f64) -> f64 {
+        let [x1, x2, y1, y2] = self.state;
+        let output =
+            self.b[0] * input + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+        self.state = [input, x1, output, y1];
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.state = [0.0; 4];
+    }
+}
+
+fn angular_frequency(frequency: f64) -> f64 {
+    2.0 * PI * frequency / SAMPLE_RATE_RAW as f64
+}
+
+/// The same biquad on both channels.
+struct Filter([Biquad; 2]);
+
+impl Filter {
+    fn new(biquad: Biquad) -> Filter {
+        Filter([biquad; 2])
+    }
+}
+
+impl AudioProcessor for Filter {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(2) {
+            for (sample, biquad) in frame.iter_mut().zip(&mut self.0) {
+                *sample = biquad.process(*sample as f64) as f32;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.0.iter_mut().for_each(Biquad::reset);
+    }
+}
+
+/// Feed-forward compressor with a soft knee. Both channels get the same gain, driven by the
+/// louder of the two, so the stereo image doesn't shift.
+struct Compressor {
+    threshold_db: f64,
+    ratio: f64,
+    knee_db: f64,
+    /// Smoothing coefficients for the gain reduction growing and shrinking.
+    attack: f64,
+    release: f64,
+    makeup_db: f64,
+    /// Current gain reduction, zero or negative.
+    reduction_db: f64,
+}
+
+impl Compressor {
+    /// Gain reduction wanted for a signal at `level_db`.
+    fn reduction(&self, level_db: f64) -> f64 {
+        let over = level_db - self.threshold_db;
+        let slope = 1.0 / self.ratio - 1.0;
+
+        if 2.0 * over < -self.knee_db {
+            0.0
+        } else if self.knee_db > 0.0 && 2.0 * over.abs() <= self.knee_db {
+            slope * (over + self.knee_db / 2.0).powi(2) / (2.0 * self.knee_db)
+        } else {
+            slope * over
+        }
+    }
+}
+
+impl AudioProcessor for Compressor {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let peak = frame[0].abs().max(frame[1].abs()) as f64;
+            let level_db = 20.0 * peak.max(1e-6).log10();
+
+            let wanted = self.reduction(level_db);
+            let coefficient = if wanted < self.reduction_db {
+                self.attack
+            } else {
+                self.release
+            };
+            self.reduction_db = wanted + coefficient * (self.reduction_db - wanted);
+
+            let gain = 10f64.powf((self.reduction_db + self.makeup_db) / 20.0) as f32;
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reduction_db = 0.0;
+    }
+}
+
+/// One-pole smoothing coefficient that covers about two thirds of a change in `ms`.
+fn smoothing_coefficient(ms: f64) -> f64 {
+    (-1000.0 / (ms * SAMPLE_RATE_RAW as f64)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = SAMPLE_RATE_RAW as f64;
+
+    /// Gain of `biquad` at `frequency` in dB, from its transfer function.
+    fn response_db(biquad: &Biquad, frequency: f64) -> f64 {
+        let w = angular_frequency(frequency);
+        // Sums c0 + c1 e^-jw + c2 e^-2jw as (real, imaginary)
+        let sum = |c: [f64; 3]| {
+            (
+                c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos(),
+                -c[1] * w.sin() - c[2] * (2.0 * w).sin(),
+            )
+        };
+        let (b_re, b_im) = sum(biquad.b);
+        let (a_re, a_im) = sum([1.0, biquad.a[0], biquad.a[1]]);
+
+        10.0 * ((b_re * b_re + b_im * b_im) / (a_re * a_re + a_im * a_im)).log10()
+    }
+
+    fn assert_db(name: &str, actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{}: {:.3} dB, expected {} dB",
+            name,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn peaking_filter_gain() {
+        for (frequency, gain_db, q) in
+            [(1_000.0, 6.0, 1.0), (250.0, -9.0, 2.0), (8_000.0, 3.0, 0.7)]
+        {
+            let biquad = Biquad::peaking(frequency, gain_db, q);
+            assert_db("Centre", response_db(&biquad, frequency), gain_db);
+            assert_db("Far below", response_db(&biquad, 1.0), 0.0);
+            assert_db("Far above", response_db(&biquad, RATE / 2.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn low_shelf_gain() {
+        for (frequency, gain_db) in [(100.0, 6.0), (200.0, -12.0)] {
+            let biquad = Biquad::low_shelf(frequency, gain_db);
+            assert_db("DC", response_db(&biquad, 0.0), gain_db);
+            assert_db("Shelf", response_db(&biquad, frequency), gain_db / 2.0);
+            assert_db("Far above", response_db(&biquad, 20_000.0), 0.0);
+        }
+    }
+
+    /// A filter processing a sine settles at the gain its transfer function gives.
+    #[test]
+    fn filter_processes_at_its_response() {
+        let mut filter = Filter::new(Biquad::peaking(1_000.0, 6.0, 1.0));
+        let mut samples: Vec<f32> = (0..RATE as usize)
+            .flat_map(|i| {
+                let sample = 0.25 * (2.0 * PI * 1_000.0 * i as f64 / RATE).sin() as f32;
+                [sample, sample]
+            })
+            .collect();
+        filter.process(&mut samples);
+
+        let peak = samples[samples.len() / 2..]
+            .iter()
+            .fold(0f32, |peak, sample| peak.max(sample.abs()));
+        assert_db("Sine", 20.0 * (peak as f64 / 0.25).log10(), 6.0);
+    }
+
+    fn compressor(knee_db: f64, attack_ms: f64, release_ms: f64) -> Compressor {
+        Compressor {
+            threshold_db: -20.0,
+            ratio: 4.0,
+            knee_db,
+            attack: smoothing_coefficient(attack_ms),
+            release: smoothing_coefficient(release_ms),
+            makeup_db: 0.0,
+            reduction_db: 0.0,
+        }
+    }
+
+    #[test]
+    fn compressor_static_curve() {
+        let soft = compressor(6.0, 10.0, 100.0);
+
+        // Below the knee nothing happens, above it the level over the threshold is divided by
+        // the ratio
+        assert_db("Below", soft.reduction(-40.0), 0.0);
+        assert_db("Knee start", soft.reduction(-23.0), 0.0);
+        assert_db("Knee end", soft.reduction(-17.0), -2.25);
+        assert_db("Above", soft.reduction(-10.0), -7.5);
+        assert_db("Far above", soft.reduction(0.0), -15.0);
+
+        // Inside the knee the curve bends gradually, reaching a quarter of the knee's reduction
+        // at the threshold
+        assert_db("Threshold", soft.reduction(-20.0), -0.5625);
+        let knee: Vec<f64> = (0..=12)
+            .map(|step| soft.reduction(-23.0 + step as f64 * 0.5))
+            .collect();
+        assert!(knee.windows(2).all(|pair| pair[1] < pair[0]));
+
+        // Without a knee the curve bends at the threshold
+        let hard = compressor(0.0, 10.0, 100.0);
+        assert_db("Hard, below", hard.reduction(-20.1), 0.0);
+        assert_db("Hard, above", hard.reduction(-12.0), -6.0);
+    }
+
+    /// Feeds `ms` of a constant level to `compressor` and returns its gain reduction after.
+    fn hold(compressor: &mut Compressor, level_db: f64, ms: f64) -> f64 {
+        let level = 10f64.powf(level_db / 20.0) as f32;
+        let mut samples = vec![level; (ms / 1000.0 * RATE) as usize * 2];
+        compressor.process(&mut samples);
+        compressor.reduction_db
+    }
+
+    #[test]
+    fn compressor_time_constants() {
+        let mut compressor = compressor(0.0, 10.0, 100.0);
+
+        // Two thirds of the way to the wanted -7.5 dB after the attack time, and all of it
+        // after several
+        let attacked = hold(&mut compressor, -10.0, 10.0);
+        assert_db("Attack", attacked, -7.5 * (1.0 - (-1f64).exp()));
+        assert_db("Attacked", hold(&mut compressor, -10.0, 100.0), -7.5);
+
+        // The same going back to no reduction, at the release time
+        let released = hold(&mut compressor, -40.0, 100.0);
+        assert_db("Release", released, -7.5 * (-1f64).exp());
+        assert_db("Released", hold(&mut compressor, -40.0, 1_000.0), 0.0);
+    }
+
+    #[test]
+    fn unchanged_effects_keep_their_state() {
+        let effects = [
+            EffectConfig::Eq {
+                frequency: 1_000.0,
+                gain_db: 6.0,
+                q: 1.0,
+            },
+            EffectConfig::Compressor {
+                threshold_db: -20.0,
+                ratio: 4.0,
+                knee_db: 6.0,
+                attack_ms: 10.0,
+                release_ms: 100.0,
+                makeup_db: 0.0,
+            },
+        ];
+        let block: Vec<f32> = (0..960).map(|i| 0.5 * (i as f32 * 0.1).sin()).collect();
+
+        let mut untouched = Chain::new(None);
+        untouched.set_effects(&effects);
+        let mut reloaded = Chain::new(None);
+        reloaded.set_effects(&effects);
+
+        untouched.process(&block);
+        reloaded.process(&block);
+        reloaded.set_effects(&effects);
+        assert_eq!(untouched.process(&block), reloaded.process(&block));
+
+        // Changed effects start over
+        let mut fresh = Chain::new(None);
+        fresh.set_effects(&effects[..1]);
+        reloaded.set_effects(&effects[..1]);
+        assert_eq!(fresh.process(&block), reloaded.process(&block));
+        assert_ne!(untouched.process(&block), fresh.process(&block));
+    }
+}
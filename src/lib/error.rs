@@ -0,0 +1,33 @@
+use librespot::core::session::SessionError;
+use thiserror::Error;
+
+/// Everything that can go wrong while running Aoede.
+#[derive(Debug, Error)]
+pub enum AoedeError {
+    /// The configuration can't be used as given.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// Spotify credentials are missing or were rejected.
+    #[error("Spotify authentication failed: {0}")]
+    Auth(String),
+    /// Spotify could not be reached. Worth retrying, unlike the other errors.
+    #[error("Spotify session error: {0}")]
+    Session(SessionError),
+    /// Joining or playing in a Discord voice channel failed.
+    #[error("Voice error: {0}")]
+    Voice(String),
+    /// The audio path between librespot and songbird failed.
+    #[error("Audio sink error: {0}")]
+    Sink(String),
+}
+
+impl From<SessionError> for AoedeError {
+    fn from(error: SessionError) -> Self {
+        match error {
+            SessionError::AuthenticationError(why) => AoedeError::Auth(why.to_string()),
+            error => AoedeError::Session(error),
+        }
+    }
+}
+
+pub type Result<T, E = AoedeError> = std::result::Result<T, E>;
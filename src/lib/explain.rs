@@ -0,0 +1,511 @@
+use figment::value::{Num, Value};
+use figment::{Error, Figment, Profile, Provider};
+
+use super::config::Config;
+
+/// A setting of [`Config`] and the keys it may be provided under.
+struct Setting {
+    field: &'static str,
+    keys: &'static [&'static str],
+    secret: bool,
+    has_default: bool,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        field: "discord_token",
+        keys: &["discord_token", "DISCORD_TOKEN"],
+        secret: true,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_auth_mode",
+        keys: &["spotify_auth_mode", "SPOTIFY_AUTH_MODE"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_username",
+        keys: &["spotify_username", "SPOTIFY_USERNAME"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "spotify_password",
+        keys: &["spotify_password", "SPOTIFY_PASSWORD"],
+        secret: true,
+        has_default: false,
+    },
+    Setting {
+        field: "discord_user_ids",
+        keys: &[
+            "discord_user_ids",
+            "discord_user_id",
+            "DISCORD_USER_ID",
+            "DISCORD_USER_IDS",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "broadcast_channel_ids",
+        keys: &[
+            "broadcast_channel_ids",
+            "broadcast_channel_id",
+            "BROADCAST_CHANNEL_ID",
+            "BROADCAST_CHANNEL_IDS",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_bot_autoplay",
+        keys: &["spotify_bot_autoplay", "SPOTIFY_BOT_AUTOPLAY"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "spotify_device_name",
+        keys: &["spotify_device_name", "SPOTIFY_DEVICE_NAME"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_bitrate",
+        keys: &["spotify_bitrate", "SPOTIFY_BITRATE"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_gapless",
+        keys: &["spotify_gapless", "SPOTIFY_GAPLESS"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation",
+        keys: &["spotify_normalisation", "SPOTIFY_NORMALISATION"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_type",
+        keys: &["spotify_normalisation_type", "SPOTIFY_NORMALISATION_TYPE"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_method",
+        keys: &[
+            "spotify_normalisation_method",
+            "SPOTIFY_NORMALISATION_METHOD",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_pregain_db",
+        keys: &[
+            "spotify_normalisation_pregain_db",
+            "SPOTIFY_NORMALISATION_PREGAIN_DB",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_threshold_dbfs",
+        keys: &[
+            "spotify_normalisation_threshold_dbfs",
+            "SPOTIFY_NORMALISATION_THRESHOLD_DBFS",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_attack_ms",
+        keys: &[
+            "spotify_normalisation_attack_ms",
+            "SPOTIFY_NORMALISATION_ATTACK_MS",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_release_ms",
+        keys: &[
+            "spotify_normalisation_release_ms",
+            "SPOTIFY_NORMALISATION_RELEASE_MS",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_normalisation_knee_db",
+        keys: &[
+            "spotify_normalisation_knee_db",
+            "SPOTIFY_NORMALISATION_KNEE_DB",
+        ],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "spotify_volume_curve",
+        keys: &["spotify_volume_curve", "SPOTIFY_VOLUME_CURVE"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "cache_dir",
+        keys: &["cache_dir", "CACHE_DIR"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "cache_credentials_dir",
+        keys: &["cache_credentials_dir", "CACHE_CREDENTIALS_DIR"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "cache_volume_dir",
+        keys: &["cache_volume_dir", "CACHE_VOLUME_DIR"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "cache_audio_dir",
+        keys: &["cache_audio_dir", "CACHE_AUDIO_DIR"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "cache_audio",
+        keys: &["cache_audio", "CACHE_AUDIO"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "cache_size_limit",
+        keys: &["cache_size_limit", "CACHE_SIZE_LIMIT"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "audio_target_latency_ms",
+        keys: &["audio_target_latency_ms", "AUDIO_TARGET_LATENCY_MS"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "audio_fade_ms",
+        keys: &["audio_fade_ms", "AUDIO_FADE_MS"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "audio_resampler",
+        keys: &["audio_resampler", "AUDIO_RESAMPLER"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "audio_resampler_quality",
+        keys: &["audio_resampler_quality", "AUDIO_RESAMPLER_QUALITY"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "audio_loudness_target_lufs",
+        keys: &["audio_loudness_target_lufs", "AUDIO_LOUDNESS_TARGET_LUFS"],
+        secret: false,
+        has_default: false,
+    },
+    Setting {
+        field: "audio_ceiling_dbtp",
+        keys: &["audio_ceiling_dbtp", "AUDIO_CEILING_DBTP"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "audio_crossfade_ms",
+        keys: &["audio_crossfade_ms", "AUDIO_CROSSFADE_MS"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "recorder_enabled",
+        keys: &["recorder_enabled", "RECORDER_ENABLED"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "recorder_dir",
+        keys: &["recorder_dir", "RECORDER_DIR"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "recorder_format",
+        keys: &["recorder_format", "RECORDER_FORMAT"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "guild",
+        keys: &["guild", "GUILD"],
+        secret: false,
+        has_default: true,
+    },
+    Setting {
+        field: "profile",
+        keys: &["profile", "PROFILE"],
+        secret: false,
+        has_default: true,
+    },
+];
+
+/// Whether `key` names a secret setting, which is redacted wherever it appears, like the
+/// passwords of profiles.
+fn is_secret(key: &str) -> bool {
+    SETTINGS.iter().any(|setting| {
+        setting.secret
+            && setting
+                .keys
+                .iter()
+                .any(|secret| secret.eq_ignore_ascii_case(key))
+    })
+}
+
+/// Describes, for every setting, the resolved value and the provider it came from.
+#[allow(clippy::result_large_err)]
+pub fn explain() -> Result<Vec<String>, Error> {
+    let figment = Config::figment();
+
+    // Surface provider failures, like an unreadable secret file, instead of reporting every
+    // setting as missing
+    figment.data()?;
+
+    Ok(SETTINGS
+        .iter()
+        .map(|setting| {
+            let provided: Vec<String> = setting
+                .keys
+                .iter()
+                .filter_map(|key| describe(&figment, setting, key))
+                .collect();
+
+            match provided.len() {
+                0 if setting.has_default => format!("{}: not set, default used", setting.field),
+                0 => format!("{}: not set", setting.field),
+                1 => format!("{}: {}", setting.field, provided[0]),
+                _ => format!(
+                    "{}: provided under several keys, keep only one of: {}",
+                    setting.field,
+                    provided.join("; ")
+                ),
+            }
+        })
+        .collect())
+}
+
+fn describe(figment: &Figment, setting: &Setting, key: &str) -> Option<String> {
+    let value = figment.find_value(key).ok()?;
+    let metadata = figment.find_metadata(key)?;
+
+    let source = match &metadata.source {
+        Some(source) => format!("{} {}", metadata.name, source),
+        None => format!(
+            "{} {}",
+            metadata.name,
+            metadata.interpolate(&Profile::Default, &[key])
+        ),
+    };
+
+    let value = if setting.secret {
+        "<redacted>".to_string()
+    } else {
+        display(&value)
+    };
+
+    Some(format!("{} (from {})", value, source))
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(_, s) => format!("{:?}", s),
+        Value::Char(_, c) => format!("{:?}", c),
+        Value::Bool(_, b) => b.to_string(),
+        Value::Num(_, num) => match *num {
+            Num::U8(n) => n.to_string(),
+            Num::U16(n) => n.to_string(),
+            Num::U32(n) => n.to_string(),
+            Num::U64(n) => n.to_string(),
+            Num::U128(n) => n.to_string(),
+            Num::USize(n) => n.to_string(),
+            Num::I8(n) => n.to_string(),
+            Num::I16(n) => n.to_string(),
+            Num::I32(n) => n.to_string(),
+            Num::I64(n) => n.to_string(),
+            Num::I128(n) => n.to_string(),
+            Num::ISize(n) => n.to_string(),
+            Num::F32(n) => n.to_string(),
+            Num::F64(n) => n.to_string(),
+        },
+        Value::Empty(..) => "(empty)".to_string(),
+        Value::Dict(_, dict) => format!(
+            "{{ {} }}",
+            dict.iter()
+                .map(|(key, value)| if is_secret(key) {
+                    format!("{} = <redacted>", key)
+                } else {
+                    format!("{} = {}", key, display(value))
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Array(_, values) => format!(
+            "[{}]",
+            values.iter().map(display).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use figment::providers::Serialized;
+    use figment::value::{Empty, Tag};
+    use serde::de::{self, value, Deserialize, Deserializer, Visitor};
+
+    use crate::lib::config::{ProfileConfig, SECRET_FILE_SETTINGS};
+
+    /// Deserializer that only records the fields of the struct it is asked for.
+    struct Fields<'a>(&'a mut Vec<&'static str>);
+
+    impl<'de> Deserializer<'de> for Fields<'_> {
+        type Error = value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, value::Error> {
+            Err(de::Error::custom("only structs are supported"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, value::Error> {
+            self.0.extend(fields);
+            Err(de::Error::custom("done"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    fn config_fields() -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        let _ = Config::deserialize(Fields(&mut fields));
+        fields
+    }
+
+    /// Extracts a config from nothing but the one setting that is required, `spotify_bot_autoplay`,
+    /// and `field` set to `value`.
+    #[allow(clippy::result_large_err)]
+    fn extract(field: &str, value: Option<Value>) -> Result<Config, Error> {
+        let mut figment = Figment::new();
+        if field != "spotify_bot_autoplay" {
+            figment = figment.merge(Serialized::default("spotify_bot_autoplay", false));
+        }
+        if let Some(value) = value {
+            figment = figment.merge(Serialized::default(field, value));
+        }
+        figment.extract()
+    }
+
+    #[test]
+    fn defaults_match_config() {
+        for setting in SETTINGS {
+            // A setting without a serde default is required, one that is optional comes out as
+            // `None` when empty, while any other type refuses an empty value
+            let required = extract(setting.field, None).is_err();
+            let optional =
+                extract(setting.field, Some(Value::Empty(Tag::Default, Empty::None))).is_ok();
+
+            assert_eq!(
+                setting.has_default,
+                !required && !optional,
+                "has_default of {} doesn't match Config",
+                setting.field
+            );
+        }
+    }
+
+    #[test]
+    fn credentials_are_secret() {
+        let mut profile_fields = Vec::new();
+        let _ = ProfileConfig::deserialize(Fields(&mut profile_fields));
+
+        for field in config_fields().into_iter().chain(profile_fields) {
+            let credential = field.ends_with("_token") || field.ends_with("_password");
+            assert_eq!(is_secret(field), credential, "{} is secret", field);
+            assert_eq!(is_secret(&field.to_uppercase()), credential);
+        }
+
+        for setting in SETTINGS.iter().filter(|setting| setting.secret) {
+            assert!(
+                SECRET_FILE_SETTINGS.contains(&setting.field),
+                "{} can't be read from a file",
+                setting.field
+            );
+        }
+    }
+
+    #[test]
+    fn every_setting_is_explained() {
+        let fields = config_fields();
+        assert!(fields.contains(&"discord_token"));
+
+        for field in &fields {
+            let setting = SETTINGS.iter().find(|setting| setting.field == *field);
+            let setting = setting.unwrap_or_else(|| panic!("{} is missing from SETTINGS", field));
+
+            assert!(
+                setting.keys.contains(field),
+                "{} is not one of its keys",
+                field
+            );
+            assert!(
+                setting.keys.contains(&field.to_uppercase().as_str()),
+                "{} can't be set from the environment",
+                field
+            );
+        }
+
+        for setting in SETTINGS {
+            assert!(
+                fields.contains(&setting.field),
+                "{} is not a setting",
+                setting.field
+            );
+        }
+    }
+
+    #[test]
+    fn secrets_are_redacted_in_tables() {
+        let dict = figment::value::Dict::from([
+            ("name".to_string(), Value::from("alice")),
+            ("spotify_password".to_string(), Value::from("hunter2")),
+            ("DISCORD_TOKEN".to_string(), Value::from("token")),
+        ]);
+
+        let shown = display(&Value::from(dict));
+        assert!(shown.contains("\"alice\""));
+        assert!(!shown.contains("hunter2"));
+        assert!(!shown.contains("token\""));
+    }
+}
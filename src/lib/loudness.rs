@@ -0,0 +1,717 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use songbird::constants::SAMPLE_RATE_RAW;
+
+use super::config::LoudnessConfig;
+use super::dsp::{AudioProcessor, Biquad};
+
+/// Frames per gating step, 100 ms as in EBU R128.
+const STEP_FRAMES: usize = SAMPLE_RATE_RAW / 10;
+/// Steps per 400 ms gating block.
+const BLOCK_STEPS: usize = 4;
+/// Blocks the loudness is integrated over. Long enough to span the quiet and loud parts of a
+/// track, short enough to follow the next one.
+const WINDOW_BLOCKS: usize = 300;
+/// Blocks quieter than this are silence and never counted.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// Range the loudness gain stays in, a quiet track isn't boosted without end.
+const MIN_GAIN_DB: f64 = -30.0;
+const MAX_GAIN_DB: f64 = 12.0;
+/// How fast the loudness gain may change, slow enough not to be heard as pumping.
+const GAIN_SLEW_DB_PER_STEP: f64 = 0.2;
+
+/// Frames the limiter looks ahead, which is also its attack time.
+const LOOKAHEAD_FRAMES: usize = SAMPLE_RATE_RAW / 200;
+const RELEASE_SECONDS: f64 = 0.2;
+
+/// Taps of each interpolation phase of the true-peak meter, enough for a flat response up to
+/// 21 kHz.
+const PEAK_TAPS: usize = 32;
+/// ITU-R BS.1770 asks for at least four times oversampling, which can under-read peaks close to
+/// 20 kHz by half a dB. Eight times keeps that to about a tenth.
+const PEAK_PHASES: usize = 8;
+/// Frames between a frame entering the meter and the meter reporting its peak.
+const PEAK_DELAY: usize = PEAK_TAPS / 2;
+/// The limiter aims this far below the ceiling, which covers what the meter can under-read for
+/// anything the low pass lets through.
+const PEAK_MARGIN_DB: f64 = 0.15;
+/// Shape of the Kaiser window of the interpolation filters.
+const PEAK_WINDOW_BETA: f64 = 6.0;
+/// Taps of the low pass in front of the limiter, odd so that it delays by whole frames.
+const LOWPASS_TAPS: usize = 63;
+/// Where the low pass is down by half. Opus cuts off at 20 kHz anyway, but what is left close
+/// to 24 kHz has no well defined true peak and would be under-read by the meter.
+const LOWPASS_HZ: f64 = 21_500.0;
+const LOWPASS_WINDOW_BETA: f64 = 6.0;
+
+/// Adjusts the stream towards a target integrated loudness and limits its true peak.
+///
+/// Loudness is measured as in EBU R128, with K-weighting and gating, but integrated over a
+/// sliding window instead of a whole programme since the sink never sees one. The gain follows
+/// the measurement slowly. A lookahead limiter then brings down whatever would still peak above
+/// the ceiling, measured with eight times oversampling so inter-sample peaks are caught before
+/// Opus turns them into clipping.
+pub struct LoudnessLimiter {
+    config: LoudnessConfig,
+    meter: Meter,
+    /// Current loudness gain, and how much it changes per frame until the next step.
+    gain: f64,
+    gain_db: f64,
+    gain_step: f64,
+    limiter: Limiter,
+}
+
+impl LoudnessLimiter {
+    pub fn new(config: LoudnessConfig) -> LoudnessLimiter {
+        LoudnessLimiter {
+            config,
+            meter: Meter::new(),
+            gain: 1.0,
+            gain_db: 0.0,
+            gain_step: 0.0,
+            limiter: Limiter::new(config.ceiling_dbtp),
+        }
+    }
+
+    /// Moves the gain towards the target once per gating step. `loudness` is `None` while
+    /// everything measured so far was gated out.
+    fn follow(&mut self, loudness: Option<f64>) {
+        if let Some(loudness) = loudness {
+            let wanted = (self.config.target_lufs - loudness).clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+            self.gain_db +=
+                (wanted - self.gain_db).clamp(-GAIN_SLEW_DB_PER_STEP, GAIN_SLEW_DB_PER_STEP);
+        }
+
+        self.gain_step = (db_to_ratio(self.gain_db) - self.gain) / STEP_FRAMES as f64;
+    }
+}
+
+/// The output is delayed by the limiter's lookahead, `drain` gives out the rest.
+impl AudioProcessor for LoudnessLimiter {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let (left, right) = (frame[0] as f64, frame[1] as f64);
+
+            if let Some(loudness) = self.meter.push(left, right) {
+                self.follow(loudness);
+            }
+
+            self.gain += self.gain_step;
+
+            frame.copy_from_slice(&self.limiter.push([left * self.gain, right * self.gain]));
+        }
+    }
+
+    /// Pushes silence through the limiter to give out the frames it holds back.
+    fn drain(&mut self, samples: &mut Vec<f32>) {
+        for _ in 0..self.limiter.delay() {
+            samples.extend_from_slice(&self.limiter.push([0.0, 0.0]));
+        }
+    }
+
+    fn delay(&self) -> usize {
+        self.limiter.delay()
+    }
+
+    /// Drops the audio held by the limiter. The loudness measurement is kept, so levels don't
+    /// jump after a pause or a seek.
+    fn reset(&mut self) {
+        self.limiter = Limiter::new(self.config.ceiling_dbtp);
+    }
+}
+
+fn db_to_ratio(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// Gated loudness of the last `WINDOW_BLOCKS` blocks, as in ITU-R BS.1770.
+struct Meter {
+    filters: [KWeighting; 2],
+    /// Sum of the squared, weighted samples of the current step.
+    step_energy: f64,
+    step_frames: usize,
+    /// Energy of the last steps, which make up the current block.
+    steps: VecDeque<f64>,
+    /// Mean square of every block in the window.
+    blocks: VecDeque<f64>,
+}
+
+impl Meter {
+    fn new() -> Meter {
+        Meter {
+            filters: [KWeighting::new(), KWeighting::new()],
+            step_energy: 0.0,
+            step_frames: 0,
+            steps: VecDeque::with_capacity(BLOCK_STEPS),
+            blocks: VecDeque::with_capacity(WINDOW_BLOCKS),
+        }
+    }
+
+    /// Adds a frame. At the end of every step, returns the loudness of the window, or `None`
+    /// inside the first step of a full block.
+    fn push(&mut self, left: f64, right: f64) -> Option<Option<f64>> {
+        let left = self.filters[0].process(left);
+        let right = self.filters[1].process(right);
+        self.step_energy += left * left + right * right;
+        self.step_frames += 1;
+
+        if self.step_frames < STEP_FRAMES {
+            return None;
+        }
+
+        if self.steps.len() == BLOCK_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy);
+        self.step_energy = 0.0;
+        self.step_frames = 0;
+
+        if self.steps.len() < BLOCK_STEPS {
+            return Some(None);
+        }
+
+        if self.blocks.len() == WINDOW_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.blocks
+            .push_back(self.steps.iter().sum::<f64>() / (BLOCK_STEPS * STEP_FRAMES) as f64);
+
+        Some(self.loudness())
+    }
+
+    fn loudness(&self) -> Option<f64> {
+        let absolute = gated_mean(&self.blocks, lufs_to_power(ABSOLUTE_GATE_LUFS))?;
+        let relative = gated_mean(&self.blocks, absolute * 10f64.powf(RELATIVE_GATE_LU / 10.0))?;
+        Some(power_to_lufs(relative))
+    }
+}
+
+fn gated_mean(blocks: &VecDeque<f64>, threshold: f64) -> Option<f64> {
+    let (sum, count) = blocks
+        .iter()
+        .filter(|&&power| power > threshold)
+        .fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+
+    (count > 0).then(|| sum / count as f64)
+}
+
+fn power_to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn lufs_to_power(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// The two K-weighting filters of ITU-R BS.1770, a high shelf modelling the head followed by a
+/// high pass. The coefficients are the ones the standard gives for 48 kHz.
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new() -> KWeighting {
+        KWeighting {
+            shelf: Biquad::new(
+                [
+                    1.535_124_859_586_97,
+                    -2.691_696_189_406_38,
+                    1.198_392_810_852_85,
+                ],
+                [-1.690_659_293_182_41, 0.732_480_774_215_85],
+            ),
+            high_pass: Biquad::new(
+                [1.0, -2.0, 1.0],
+                [-1.990_047_454_833_98, 0.990_072_250_366_21],
+            ),
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+/// Lookahead true-peak limiter, behind a low pass that keeps the stream clear of 24 kHz.
+///
+/// The gain for every frame is the minimum gain needed in the lookahead window around it,
+/// released slowly and then smoothed by a moving average as long as the lookahead. Since the
+/// average only covers gains at or below the one a peak needs, the peak never ends up above the
+/// ceiling, and nothing needs to be clipped afterwards.
+struct Limiter {
+    ceiling: f64,
+    release: f64,
+    /// Last `LOWPASS_TAPS` frames per channel, before the low pass.
+    unfiltered: [[f64; LOWPASS_TAPS]; 2],
+    lowpass: [f64; LOWPASS_TAPS],
+    /// Last `PEAK_TAPS` frames per channel, for interpolating between them.
+    history: [[f64; PEAK_TAPS]; 2],
+    /// Interpolation filters for the positions between two frames.
+    phases: [[f64; PEAK_TAPS]; PEAK_PHASES - 1],
+    frames: u64,
+    /// Increasing gains needed over the lookahead window with the frame they are needed at, the
+    /// front is the window's minimum.
+    needed: VecDeque<(u64, f64)>,
+    released: f64,
+    /// Gains averaged over the lookahead, and their sum.
+    smoothing: VecDeque<f64>,
+    smoothed: f64,
+    /// Frames waiting for their gain.
+    delayed: VecDeque<[f64; 2]>,
+}
+
+impl Limiter {
+    fn new(ceiling_dbtp: f64) -> Limiter {
+        Limiter {
+            ceiling: db_to_ratio(ceiling_dbtp - PEAK_MARGIN_DB),
+            release: 1.0 - (-1.0 / (RELEASE_SECONDS * SAMPLE_RATE_RAW as f64)).exp(),
+            unfiltered: [[0.0; LOWPASS_TAPS]; 2],
+            lowpass: lowpass(),
+            history: [[0.0; PEAK_TAPS]; 2],
+            phases: interpolation_phases(),
+            frames: 0,
+            needed: VecDeque::with_capacity(LOOKAHEAD_FRAMES + 1),
+            released: 1.0,
+            smoothing: VecDeque::from(vec![1.0; LOOKAHEAD_FRAMES]),
+            smoothed: LOOKAHEAD_FRAMES as f64,
+            delayed: VecDeque::from(vec![[0.0; 2]; Self::DELAY]),
+        }
+    }
+
+    const DELAY: usize = LOOKAHEAD_FRAMES - 1 + PEAK_DELAY;
+
+    /// Frames between one entering the limiter and leaving it.
+    fn delay(&self) -> usize {
+        Self::DELAY + LOWPASS_TAPS / 2
+    }
+
+    fn push(&mut self, frame: [f64; 2]) -> [f32; 2] {
+        let frame = self.filter(frame);
+        let gain = self.needed_gain(frame);
+
+        // The window is one longer than the average, so the gain at a peak also covers the
+        // interpolated peak leading up to the next frame
+        while self
+            .needed
+            .back()
+            .is_some_and(|&(_, needed)| needed >= gain)
+        {
+            self.needed.pop_back();
+        }
+        self.needed.push_back((self.frames, gain));
+        while self
+            .needed
+            .front()
+            .is_some_and(|&(frame, _)| frame + (LOOKAHEAD_FRAMES as u64) < self.frames)
+        {
+            self.needed.pop_front();
+        }
+        self.frames += 1;
+
+        let needed = self.needed.front().map_or(1.0, |&(_, needed)| needed);
+        self.released = needed.min(self.released + (1.0 - self.released) * self.release);
+
+        self.smoothed += self.released - self.smoothing.pop_front().unwrap_or(1.0);
+        self.smoothing.push_back(self.released);
+        let gain = (self.smoothed / LOOKAHEAD_FRAMES as f64).min(1.0);
+
+        self.delayed.push_back(frame);
+        let [left, right] = self.delayed.pop_front().unwrap_or_default();
+
+        [(left * gain) as f32, (right * gain) as f32]
+    }
+
+    /// Low passes `frame`, delaying it by half the filter.
+    fn filter(&mut self, frame: [f64; 2]) -> [f64; 2] {
+        let mut filtered = [0.0; 2];
+
+        for ((unfiltered, sample), filtered) in
+            self.unfiltered.iter_mut().zip(frame).zip(&mut filtered)
+        {
+            unfiltered.copy_within(1.., 0);
+            unfiltered[LOWPASS_TAPS - 1] = sample;
+            *filtered = dot(&self.lowpass, unfiltered);
+        }
+
+        filtered
+    }
+
+    /// Gain that keeps the true peak between the frames `PEAK_DELAY` and `PEAK_DELAY - 1`
+    /// before `frame` under the ceiling.
+    fn needed_gain(&mut self, frame: [f64; 2]) -> f64 {
+        let mut peak: f64 = 0.0;
+
+        for (history, sample) in self.history.iter_mut().zip(frame) {
+            history.copy_within(1.., 0);
+            history[PEAK_TAPS - 1] = sample;
+
+            peak = peak.max(history[PEAK_TAPS - 1 - PEAK_DELAY].abs());
+            for phase in &self.phases {
+                peak = peak.max(dot(phase, history).abs());
+            }
+        }
+
+        if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Kaiser windowed sinc low pass at `LOWPASS_HZ`.
+fn lowpass() -> [f64; LOWPASS_TAPS] {
+    let mut coefficients = [0.0; LOWPASS_TAPS];
+    let half_width = (LOWPASS_TAPS / 2 + 1) as f64;
+    let cutoff = LOWPASS_HZ / SAMPLE_RATE_RAW as f64;
+
+    for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+        // Distance from the filter's center
+        let x = tap as f64 - (LOWPASS_TAPS / 2) as f64;
+        let sinc = if x == 0.0 {
+            2.0 * cutoff
+        } else {
+            (2.0 * PI * cutoff * x).sin() / (PI * x)
+        };
+        let window = bessel_i0(LOWPASS_WINDOW_BETA * (1.0 - (x / half_width).powi(2)).sqrt())
+            / bessel_i0(LOWPASS_WINDOW_BETA);
+        *coefficient = sinc * window;
+    }
+
+    // Unity gain at DC
+    let sum: f64 = coefficients.iter().sum();
+    coefficients.iter_mut().for_each(|c| *c /= sum);
+
+    coefficients
+}
+
+/// Kaiser windowed sinc filters for the positions in between the frame `PEAK_DELAY` frames back
+/// and the one after it.
+fn interpolation_phases() -> [[f64; PEAK_TAPS]; PEAK_PHASES - 1] {
+    let mut phases = [[0.0; PEAK_TAPS]; PEAK_PHASES - 1];
+    let half_width = PEAK_TAPS as f64 / 2.0;
+
+    for (phase, coefficients) in phases.iter_mut().enumerate() {
+        let fraction = (phase + 1) as f64 / PEAK_PHASES as f64;
+
+        for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+            // Distance from the interpolated position to this tap's frame
+            let x = (PEAK_TAPS - 1 - PEAK_DELAY) as f64 + fraction - tap as f64;
+            let sinc = (PI * x).sin() / (PI * x);
+            let window = bessel_i0(PEAK_WINDOW_BETA * (1.0 - (x / half_width).powi(2)).sqrt())
+                / bessel_i0(PEAK_WINDOW_BETA);
+            *coefficient = sinc * window;
+        }
+
+        // Unity gain at DC
+        let sum: f64 = coefficients.iter().sum();
+        coefficients.iter_mut().for_each(|c| *c /= sum);
+    }
+
+    phases
+}
+
+/// Dot product in four independent lanes, which the compiler can vectorize.
+fn dot<const N: usize>(a: &[f64; N], b: &[f64; N]) -> f64 {
+    let mut lanes = [0.0; 4];
+    let (a, b) = (a.chunks_exact(4), b.chunks_exact(4));
+    let remainder: f64 = a
+        .remainder()
+        .iter()
+        .zip(b.remainder())
+        .map(|(a, b)| a * b)
+        .sum();
+
+    for (a, b) in a.zip(b) {
+        for lane in 0..4 {
+            lanes[lane] += a[lane] * b[lane];
+        }
+    }
+
+    lanes.iter().sum::<f64>() + remainder
+}
+
+/// Zeroth order modified Bessel function of the first kind, from its power series.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+
+    for k in 1..32 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+    }
+
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CEILING_DBTP: f64 = -1.0;
+    const RATE: f64 = SAMPLE_RATE_RAW as f64;
+
+    /// Taps of the reference meter's interpolation filters, twice as long as the limiter's own.
+    const REFERENCE_TAPS: usize = 256;
+    const REFERENCE_PHASES: usize = 8;
+    const REFERENCE_WINDOW_BETA: f64 = 9.0;
+
+    /// True peak of interleaved stereo samples in dBTP, measured independently of the limiter
+    /// with eight times oversampling.
+    fn true_peak_db(samples: &[f32]) -> f64 {
+        let half = REFERENCE_TAPS / 2;
+        let phases: Vec<Vec<f64>> = (1..REFERENCE_PHASES)
+            .map(|phase| {
+                let fraction = phase as f64 / REFERENCE_PHASES as f64;
+                (0..REFERENCE_TAPS)
+                    .map(|tap| {
+                        // Distance from the interpolated position to this tap's frame
+                        let x = (half - 1) as f64 + fraction - tap as f64;
+                        let window = bessel_i0(
+                            REFERENCE_WINDOW_BETA * (1.0 - (x / half as f64).powi(2)).sqrt(),
+                        ) / bessel_i0(REFERENCE_WINDOW_BETA);
+                        (PI * x).sin() / (PI * x) * window
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut peak: f64 = 0.0;
+        for channel in 0..2 {
+            // Padded with silence, so that every position has a full window around it
+            let mut padded = vec![0.0; half];
+            padded.extend(samples.iter().skip(channel).step_by(2).map(|s| *s as f64));
+            padded.extend(vec![0.0; half]);
+
+            for window in padded.windows(REFERENCE_TAPS) {
+                peak = peak.max(window[half - 1].abs());
+                for phase in &phases {
+                    let interpolated: f64 = window.iter().zip(phase).map(|(s, c)| s * c).sum();
+                    peak = peak.max(interpolated.abs());
+                }
+            }
+        }
+
+        20.0 * peak.log10()
+    }
+
+    /// Runs `frames` through a limiter and gives out everything it holds back as well, down to
+    /// the low pass ringing after the last frame. Cutting that off would read as a step.
+    fn limit(frames: impl IntoIterator<Item = [f64; 2]>) -> Vec<f32> {
+        let mut limiter = Limiter::new(CEILING_DBTP);
+        let mut output = Vec::new();
+
+        for frame in frames {
+            output.extend_from_slice(&limiter.push(frame));
+        }
+        for _ in 0..limiter.delay() + LOWPASS_TAPS / 2 {
+            output.extend_from_slice(&limiter.push([0.0, 0.0]));
+        }
+
+        output
+    }
+
+    fn sine(hz: f64, amplitude: f64, phase: f64, seconds: f64) -> impl Iterator<Item = [f64; 2]> {
+        (0..(seconds * RATE) as usize).map(move |i| {
+            let sample = amplitude * (2.0 * PI * hz * i as f64 / RATE + phase).sin();
+            [sample, -sample]
+        })
+    }
+
+    fn silence(seconds: f64) -> impl Iterator<Item = [f64; 2]> {
+        std::iter::repeat_n([0.0, 0.0], (seconds * RATE) as usize)
+    }
+
+    fn assert_under_ceiling(name: &str, output: &[f32]) {
+        let peak = true_peak_db(output);
+        assert!(
+            peak <= CEILING_DBTP,
+            "{} peaks at {:.3} dBTP, above the ceiling of {} dBTP",
+            name,
+            peak,
+            CEILING_DBTP
+        );
+    }
+
+    #[test]
+    fn hot_sines() {
+        for (hz, gain_db) in [
+            (100.0, 6.0),
+            (997.0, 12.0),
+            (5_000.0, 6.0),
+            (15_000.0, 3.0),
+            (22_000.0, 20.0),
+        ] {
+            let output = limit(sine(hz, db_to_ratio(gain_db), 0.0, 0.25));
+            assert_under_ceiling(&format!("{} Hz at +{} dBFS", hz, gain_db), &output);
+        }
+    }
+
+    /// Tones at a quarter of the rate and close to it, sampled between their peaks so that the
+    /// samples read up to 3 dB lower than the true peak.
+    #[test]
+    fn inter_sample_peaks() {
+        for hz in [11_000.0, 12_000.0] {
+            for phase in [PI / 4.0, PI / 8.0] {
+                let output = limit(sine(hz, 2f64.sqrt(), phase, 0.25));
+                assert_under_ceiling(&format!("{} Hz, phase {:.2}", hz, phase), &output);
+            }
+        }
+    }
+
+    #[test]
+    fn bursts_after_silence() {
+        let frames = silence(0.2)
+            .chain(sine(5_000.0, 4.0, 0.0, 0.1))
+            .chain(silence(0.2))
+            .chain(sine(12_000.0, 2.0, PI / 4.0, 0.1))
+            .chain(silence(0.01))
+            .chain(sine(997.0, 1.5, PI / 2.0, 0.05));
+        assert_under_ceiling("Bursts", &limit(frames));
+    }
+
+    /// White noise and square waves, which put as much into the top of the band as anything will.
+    #[test]
+    fn full_band() {
+        // A fixed linear congruential generator, so that every run sees the same noise
+        let mut seed = 1u64;
+        let mut noise = move || {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+        };
+        let frames: Vec<[f64; 2]> = (0..(0.25 * RATE) as usize)
+            .map(|_| [noise() * 4.0, noise() * 4.0])
+            .collect();
+        assert_under_ceiling("White noise at +12 dBFS", &limit(frames));
+
+        for period in [4, 6, 10] {
+            let frames = (0..(0.25 * RATE) as usize).map(|i| {
+                let sample = if i % period < period / 2 { 1.5 } else { -1.5 };
+                [sample, sample]
+            });
+            assert_under_ceiling(&format!("Square wave, period {}", period), &limit(frames));
+        }
+    }
+
+    #[test]
+    fn impulses() {
+        let mut frames: Vec<[f64; 2]> = silence(0.6).collect();
+        // A single impulse, a pair of opposite ones and a pair of equal ones, the last two peak
+        // well between the samples
+        frames[4_800] = [2.0, -2.0];
+        frames[9_600] = [1.0, 1.0];
+        frames[9_601] = [-1.0, -1.0];
+        frames[14_400] = [1.0, 1.0];
+        frames[14_401] = [1.0, 1.0];
+        // Impulses on top of a loud tone, after the limiter has started to release
+        for (i, frame) in sine(997.0, 1.0, 0.0, 0.2).enumerate() {
+            frames[19_200 + i] = frame;
+        }
+        frames[19_200 + 4_000] = [3.0, -3.0];
+        // Alternating impulses, all of their energy close to 24 kHz
+        for i in 0..8 {
+            let sample = if i % 2 == 0 { 4.0 } else { -4.0 };
+            frames[26_400 + i] = [sample, sample];
+        }
+        assert_under_ceiling("Impulses", &limit(frames));
+    }
+
+    /// Loudness of interleaved stereo samples, as read by a fresh meter.
+    fn loudness(samples: &[f32]) -> f64 {
+        let mut meter = Meter::new();
+        for frame in samples.chunks_exact(2) {
+            meter.push(frame[0] as f64, frame[1] as f64);
+        }
+        meter.loudness().unwrap()
+    }
+
+    /// EBU Tech 3341, test cases 1 and 2: a 1 kHz sine at -23 and -33 dBFS on both channels
+    /// reads -23 and -33 LUFS, within 0.1 LU.
+    #[test]
+    fn meter_reads_reference_sines() {
+        for level in [-23.0, -33.0] {
+            let samples: Vec<f32> = sine(1_000.0, db_to_ratio(level), 0.0, 20.0)
+                .flat_map(|[left, _]| [left as f32, left as f32])
+                .collect();
+
+            let measured = loudness(&samples);
+            assert!(
+                (measured - level).abs() < 0.1,
+                "A 1 kHz sine at {} dBFS reads {:.2} LUFS",
+                level,
+                measured
+            );
+        }
+    }
+
+    /// Runs a 1 kHz sine at `level` dBFS through a loudness limiter for `seconds`, returning
+    /// the output and the loudness gain after every step.
+    fn follow(config: LoudnessConfig, level: f64, seconds: f64) -> (Vec<f32>, Vec<f64>) {
+        let mut limiter = LoudnessLimiter::new(config);
+        let mut output = Vec::new();
+        let mut gains = Vec::new();
+
+        let frames: Vec<f32> = sine(1_000.0, db_to_ratio(level), 0.0, seconds)
+            .flat_map(|[left, right]| [left as f32, right as f32])
+            .collect();
+        for step in frames.chunks(STEP_FRAMES * 2) {
+            let mut step = step.to_vec();
+            limiter.process(&mut step);
+            output.extend_from_slice(&step);
+            gains.push(limiter.gain_db);
+        }
+
+        (output, gains)
+    }
+
+    #[test]
+    fn gain_converges_on_the_target() {
+        let config = LoudnessConfig {
+            target_lufs: -16.0,
+            ceiling_dbtp: CEILING_DBTP,
+        };
+        let (output, gains) = follow(config, -24.0, 8.0);
+
+        // The gain rises towards +8 dB no faster than its slew rate, and settles there without
+        // going past it
+        assert!(gains
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] <= GAIN_SLEW_DB_PER_STEP + 1e-9));
+        assert!(gains.iter().all(|gain| *gain <= 8.0 + 0.05));
+        assert!(gains[gains.len() * 3 / 4..]
+            .iter()
+            .all(|gain| (gain - 8.0).abs() < 0.05));
+
+        let settled = &output[output.len() - 2 * SAMPLE_RATE_RAW * 2..];
+        let measured = loudness(settled);
+        assert!(
+            (measured - config.target_lufs).abs() < 0.1,
+            "Settled at {:.2} LUFS",
+            measured
+        );
+    }
+
+    #[test]
+    fn ceiling_holds_when_the_target_is_above_it() {
+        // Reaching the target takes the sine 2 dB over the ceiling, the limiter keeps it under
+        let config = LoudnessConfig {
+            target_lufs: -10.0,
+            ceiling_dbtp: -12.0,
+        };
+        let (output, gains) = follow(config, -20.0, 6.0);
+        assert!((gains[gains.len() - 1] - 10.0).abs() < 0.05);
+
+        let settled = &output[output.len() - SAMPLE_RATE_RAW..];
+        let peak = true_peak_db(settled);
+        assert!(
+            peak <= config.ceiling_dbtp && peak > config.ceiling_dbtp - 0.5,
+            "Settled with a true peak of {:.2} dBTP",
+            peak
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use librespot::playback::config::VolumeCtrl;
+use librespot::playback::mixer::{mappings::MappedCtrl, Mixer, MixerConfig};
+
+use serenity::model::id::GuildId;
+use songbird::tracks::TrackHandle;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::warn;
+
+/// Applies the Spotify Connect volume to the songbird tracks playing the stream, after
+/// resampling, instead of scaling samples inside librespot.
+///
+/// Clones share their state, so the volume survives the Connect device being restarted.
+#[derive(Clone)]
+pub struct TrackMixer {
+    volume_ctrl: VolumeCtrl,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    /// Connect volume, from 0 to `VolumeCtrl::MAX_VOLUME`.
+    volume: u16,
+    /// Track playing in each guild, and the guild's volume multiplied with the Connect volume.
+    tracks: HashMap<GuildId, (TrackHandle, f32)>,
+}
+
+impl TrackMixer {
+    /// Starts applying the volume to `track` playing in `guild_id`, scaled by the guild's
+    /// `gain`.
+    pub fn attach(&self, guild_id: GuildId, track: TrackHandle, gain: f32) {
+        let mut state = self.state.lock().unwrap();
+        state.tracks.insert(guild_id, (track, gain));
+        self.apply(&mut state);
+    }
+
+    /// Forgets the track of a guild that was left.
+    pub fn detach(&self, guild_id: GuildId) {
+        self.state.lock().unwrap().tracks.remove(&guild_id);
+    }
+
+    /// Whether Spotify should offer a volume slider at all.
+    pub fn has_volume_ctrl(&self) -> bool {
+        !matches!(self.volume_ctrl, VolumeCtrl::Fixed)
+    }
+
+    /// Amplitude factor the Connect volume currently amounts to, for output without a songbird
+    /// track.
+    pub fn factor(&self) -> f32 {
+        self.factor_of(self.state.lock().unwrap().volume)
+    }
+
+    fn factor_of(&self, volume: u16) -> f32 {
+        match self.volume_ctrl {
+            VolumeCtrl::Fixed => 1.0,
+            volume_ctrl => volume_ctrl.to_mapped(volume) as f32,
+        }
+    }
+
+    fn apply(&self, state: &mut State) {
+        let factor = self.factor_of(state.volume);
+
+        // A track is gone once the bot left the channel, forget it until the next one
+        state
+            .tracks
+            .retain(|_, (track, gain)| match track.set_volume(*gain * factor) {
+                Ok(()) => true,
+                Err(why) => {
+                    warn!(error = %why, "Could not set track volume");
+                    false
+                }
+            });
+    }
+}
+
+impl Mixer for TrackMixer {
+    fn open(config: MixerConfig) -> Self {
+        TrackMixer {
+            volume_ctrl: config.volume_ctrl,
+            state: Arc::new(Mutex::new(State {
+                // Half amplitude until Spotify or the volume cache say otherwise
+                volume: config.volume_ctrl.to_unmapped(0.5),
+                tracks: HashMap::new(),
+            })),
+        }
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.volume = volume;
+        self.apply(&mut state);
+    }
+
+    fn volume(&self) -> u16 {
+        self.state.lock().unwrap().volume
+    }
+}
@@ -0,0 +1,382 @@
+use byteorder::{ByteOrder, LittleEndian};
+use songbird::constants::{FRAME_LEN_MS, STEREO_FRAME_SIZE};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use super::config::Config;
+use super::error::{AoedeError, Result};
+use super::mixer::TrackMixer;
+use super::player::SpotifyPlayer;
+use super::session;
+use super::sink::EmittedSink;
+use super::wav::{wav_header, PcmFormat};
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What `aoede --output pipe` writes and where.
+pub struct PipeOptions {
+    /// Prefix the stream with a WAV header instead of writing bare samples.
+    pub wav: bool,
+    pub format: PcmFormat,
+    /// File or named pipe to write to, stdout if `None`.
+    pub path: Option<PathBuf>,
+    /// Profile to play, the first one if `None`.
+    pub profile: Option<String>,
+}
+
+impl PipeOptions {
+    /// Parses the arguments following `--output pipe`.
+    pub fn parse(args: &[&str]) -> std::result::Result<PipeOptions, String> {
+        let mut options = PipeOptions {
+            wav: false,
+            format: PcmFormat::F32,
+            path: None,
+            profile: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(&flag) = args.next() {
+            let value = *args
+                .next()
+                .ok_or_else(|| format!("Missing value for '{}'", flag))?;
+
+            match (flag, value) {
+                ("--format", "raw") => options.wav = false,
+                ("--format", "wav") => options.wav = true,
+                ("--sample", "f32") => options.format = PcmFormat::F32,
+                ("--sample", "s16") => options.format = PcmFormat::S16,
+                ("--path", path) => options.path = Some(path.into()),
+                ("--profile", profile) => options.profile = Some(profile.to_string()),
+                _ => return Err(format!("Unexpected argument '{} {}'", flag, value)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// Runs a profile as a Spotify Connect device without Discord and writes its audio to a pipe in
+/// real time, silence included while nothing plays. Returns once stdout is closed.
+pub async fn run(config: &Config, options: PipeOptions) -> Result<()> {
+    let profile = match &options.profile {
+        Some(name) => config
+            .profile(name)
+            .ok_or_else(|| AoedeError::Config(format!("No profile named '{}'", name)))?,
+        None => config.profiles().remove(0),
+    };
+
+    let mut player = SpotifyPlayer::new(
+        &profile,
+        config.player_config(),
+        config.mixer_config(),
+        config.audio_config(),
+        config.cache_paths(&profile),
+    )
+    .await?;
+
+    player
+        .emitted_sink
+        .set_recording(config.recording(&profile.name));
+    player.enable_connect().await;
+
+    let sink = player.emitted_sink.clone();
+    let mixer = player.mixer.clone();
+
+    let player = Arc::new(Mutex::new(player));
+    session::supervise(player.clone());
+    tokio::spawn(drain_events(player));
+
+    info!(profile = %profile.name, "Streaming to {}", describe(&options));
+
+    tokio::task::spawn_blocking(move || write_paced(sink, mixer, &options))
+        .await
+        .map_err(|why| AoedeError::Sink(why.to_string()))?
+        .map_err(|why| AoedeError::Sink(format!("Could not write audio: {}", why)))
+}
+
+/// Receives the player's events so that they don't pile up. The sink follows the player on
+/// its own, nothing else here needs them.
+async fn drain_events(player: Arc<Mutex<SpotifyPlayer>>) {
+    loop {
+        let channel = player.lock().await.event_channel.clone();
+        if channel.lock().await.recv().await.is_none() {
+            tokio::time::sleep(Duration::from_millis(256)).await;
+        }
+    }
+}
+
+fn describe(options: &PipeOptions) -> String {
+    let target = match &options.path {
+        Some(path) => path.display().to_string(),
+        None => "stdout".to_string(),
+    };
+
+    format!(
+        "{} as {} {:?}",
+        target,
+        if options.wav { "WAV" } else { "raw" },
+        options.format
+    )
+}
+
+/// Takes a frame off the sink every `FRAME_LEN_MS`, the way songbird would, and writes it out.
+/// A closed named pipe is reopened for the next reader, a closed stdout ends the stream.
+fn write_paced(mut sink: EmittedSink, mixer: TrackMixer, options: &PipeOptions) -> io::Result<()> {
+    let frame_len = Duration::from_millis(FRAME_LEN_MS as u64);
+
+    let mut output = open(options)?;
+    let mut bytes = vec![0; STEREO_FRAME_SIZE * 4];
+    let mut samples = vec![0.0; STEREO_FRAME_SIZE];
+    let mut encoded = Vec::with_capacity(STEREO_FRAME_SIZE * 4);
+    let mut deadline = Instant::now();
+
+    loop {
+        read_frame(&mut sink, &mut bytes)?;
+        LittleEndian::read_f32_into(&bytes, &mut samples);
+
+        let factor = mixer.factor();
+        encoded.clear();
+        for sample in &samples {
+            let sample = sample * factor;
+            match options.format {
+                PcmFormat::F32 => encoded.extend_from_slice(&sample.to_le_bytes()),
+                PcmFormat::S16 => encoded.extend_from_slice(
+                    &((sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16).to_le_bytes(),
+                ),
+            }
+        }
+
+        match deliver(&mut output, &encoded, options)? {
+            Delivery::Written => {}
+            Delivery::Reopened => deadline = Instant::now(),
+            Delivery::Closed => return Ok(()),
+        }
+
+        // Catch up on short stalls, but don't burst out a backlog after a long one
+        deadline += frame_len;
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        } else if now - deadline > frame_len * 5 {
+            deadline = now;
+        }
+    }
+}
+
+enum Delivery {
+    Written,
+    /// The reader went away and the named pipe was opened again for the next one. The frame
+    /// is dropped.
+    Reopened,
+    /// Stdout was closed, the stream is over.
+    Closed,
+}
+
+/// Writes a frame to the output, reopening a named pipe whose reader went away.
+fn deliver(
+    output: &mut Box<dyn Write + Send>,
+    encoded: &[u8],
+    options: &PipeOptions,
+) -> io::Result<Delivery> {
+    match output.write_all(encoded).and_then(|_| output.flush()) {
+        Ok(()) => Ok(Delivery::Written),
+        Err(why) if why.kind() == io::ErrorKind::BrokenPipe && options.path.is_some() => {
+            info!("Reader closed the pipe, waiting for the next one");
+            *output = open(options)?;
+            Ok(Delivery::Reopened)
+        }
+        Err(why) if why.kind() == io::ErrorKind::BrokenPipe => Ok(Delivery::Closed),
+        Err(why) => Err(why),
+    }
+}
+
+/// Opens the output and writes the header, blocking on a named pipe until a reader opens it.
+fn open(options: &PipeOptions) -> io::Result<Box<dyn Write + Send>> {
+    let mut output: Box<dyn Write + Send> = match &options.path {
+        Some(path) => Box::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?,
+        ),
+        None => Box::new(io::stdout()),
+    };
+
+    if options.wav {
+        output.write_all(&wav_header(options.format, u64::MAX))?;
+    }
+
+    Ok(output)
+}
+
+/// Fills `bytes` with one frame of float samples. The sink never blocks and hands out silence
+/// while buffering, but may return less than asked for.
+fn read_frame(sink: &mut EmittedSink, bytes: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < bytes.len() {
+        filled += sink.read(&mut bytes[filled..])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[test]
+    fn options_are_parsed() {
+        let options = PipeOptions::parse(&[]).unwrap();
+        assert!(!options.wav);
+        assert_eq!(options.format, PcmFormat::F32);
+        assert_eq!(options.path, None);
+        assert_eq!(options.profile, None);
+
+        let options = PipeOptions::parse(&[
+            "--format",
+            "wav",
+            "--sample",
+            "s16",
+            "--path",
+            "/tmp/aoede",
+            "--profile",
+            "den",
+        ])
+        .unwrap();
+        assert!(options.wav);
+        assert_eq!(options.format, PcmFormat::S16);
+        assert_eq!(options.path, Some(PathBuf::from("/tmp/aoede")));
+        assert_eq!(options.profile.as_deref(), Some("den"));
+
+        // Later flags win
+        let options = PipeOptions::parse(&["--format", "wav", "--format", "raw"]).unwrap();
+        assert!(!options.wav);
+    }
+
+    #[test]
+    fn bad_options_are_rejected() {
+        let error = |args: &[&str]| PipeOptions::parse(args).err().unwrap();
+
+        assert_eq!(error(&["--path"]), "Missing value for '--path'");
+        assert_eq!(
+            error(&["--format", "wav", "--sample"]),
+            "Missing value for '--sample'"
+        );
+        assert_eq!(
+            error(&["--format", "flac"]),
+            "Unexpected argument '--format flac'"
+        );
+        assert_eq!(
+            error(&["--volume", "1"]),
+            "Unexpected argument '--volume 1'"
+        );
+    }
+
+    fn fifo(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("aoede-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        path
+    }
+
+    /// Opens `path` for reading on another thread, blocking until a writer opens it, and
+    /// returns the first `len` bytes written.
+    fn read_from(path: &Path, len: usize) -> thread::JoinHandle<Vec<u8>> {
+        let path = path.to_owned();
+        thread::spawn(move || {
+            let mut bytes = vec![0; len];
+            File::open(path).unwrap().read_exact(&mut bytes).unwrap();
+            bytes
+        })
+    }
+
+    fn wav_options(path: &Path) -> PipeOptions {
+        PipeOptions {
+            wav: true,
+            format: PcmFormat::S16,
+            path: Some(path.to_owned()),
+            profile: None,
+        }
+    }
+
+    #[test]
+    fn closed_fifo_is_reopened_for_the_next_reader() {
+        let first = fifo("pipe-first");
+        let second = fifo("pipe-second");
+        let header = wav_header(PcmFormat::S16, u64::MAX);
+
+        let reader = read_from(&first, header.len() + 4);
+        let mut output = open(&wav_options(&first)).unwrap();
+        assert!(matches!(
+            deliver(&mut output, &[1, 2, 3, 4], &wav_options(&first)).unwrap(),
+            Delivery::Written
+        ));
+        assert_eq!(
+            reader.join().unwrap(),
+            [&header[..], &[1, 2, 3, 4]].concat()
+        );
+
+        // The reader is gone, so the next write fails with a broken pipe. The new reader waits
+        // on another FIFO so it can't open the first one before that write
+        let reader = read_from(&second, header.len() + 4);
+        assert!(matches!(
+            deliver(&mut output, &[5, 6, 7, 8], &wav_options(&second)).unwrap(),
+            Delivery::Reopened
+        ));
+        assert!(matches!(
+            deliver(&mut output, &[9, 10, 11, 12], &wav_options(&second)).unwrap(),
+            Delivery::Written
+        ));
+
+        // Each reader gets a header of its own, and the frame that failed is dropped
+        assert_eq!(
+            reader.join().unwrap(),
+            [&header[..], &[9, 10, 11, 12]].concat()
+        );
+
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+
+    struct Failing(io::ErrorKind);
+
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(self.0.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn closed_stdout_ends_the_stream() {
+        let stdout = PipeOptions::parse(&[]).unwrap();
+
+        let mut output: Box<dyn Write + Send> = Box::new(Failing(io::ErrorKind::BrokenPipe));
+        assert!(matches!(
+            deliver(&mut output, &[0; 4], &stdout).unwrap(),
+            Delivery::Closed
+        ));
+
+        let mut output: Box<dyn Write + Send> = Box::new(Failing(io::ErrorKind::Other));
+        assert_eq!(
+            deliver(&mut output, &[0; 4], &stdout).err().unwrap().kind(),
+            io::ErrorKind::Other
+        );
+    }
+}
@@ -1,268 +1,113 @@
 use librespot::connect::spirc::Spirc;
 use librespot::core::{
-    authentication::Credentials,
     cache::Cache,
-    config::{ConnectConfig, DeviceType, SessionConfig},
+    config::{ConnectConfig, DeviceType},
     session::Session,
 };
 use librespot::playback::{
-    audio_backend,
-    audio_backend::SinkResult,
-    config::Bitrate,
-    config::{PlayerConfig, VolumeCtrl},
-    convert::Converter,
-    decoder::AudioPacket,
-    mixer::softmixer::SoftMixer,
-    mixer::{Mixer, MixerConfig},
+    config::PlayerConfig,
+    mixer::{Mixer, MixerConfig, NoOpVolume},
     player::{Player, PlayerEventChannel},
 };
 
+use serenity::model::id::GuildId;
 use serenity::prelude::TypeMapKey;
 
-use std::clone::Clone;
-use std::sync::{
-    mpsc::{sync_channel, Receiver, SyncSender},
-    Arc, Mutex,
-};
-use std::{io, mem};
+use super::config::{AudioConfig, CachePaths, Profile};
+use super::error::Result;
+use super::mixer::TrackMixer;
+use super::session;
+use super::sink::EmittedSink;
+
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use byteorder::{ByteOrder, LittleEndian};
-use rubato::{FftFixedInOut, Resampler};
-use songbird::input::reader::MediaSource;
+use tracing::{info, warn};
 
 pub struct SpotifyPlayer {
+    /// Name of the profile this player belongs to.
+    pub name: String,
+    /// Profile and cache the session is reconnected with.
+    profile: Profile,
+    cache: Option<Cache>,
+    /// Guild the player is currently streaming into.
+    pub guild_id: Option<GuildId>,
+    /// Other guilds the stream is broadcast into, with the sink playing into each.
+    pub broadcast_guilds: Vec<(GuildId, EmittedSink)>,
     player_config: PlayerConfig,
     pub emitted_sink: EmittedSink,
     pub session: Session,
     pub spirc: Option<Box<Spirc>>,
-    pub event_channel: Option<Arc<tokio::sync::Mutex<PlayerEventChannel>>>,
-    mixer: Box<SoftMixer>,
+    pub event_channel: Arc<tokio::sync::Mutex<PlayerEventChannel>>,
+    /// Applies the Connect volume to the songbird tracks.
+    pub mixer: TrackMixer,
     pub bot_autoplay: bool,
     pub device_name: String,
 }
 
-pub struct EmittedSink {
-    sender: Arc<SyncSender<[f32; 2]>>,
-    pub receiver: Arc<Mutex<Receiver<[f32; 2]>>>,
-    input_buffer: Arc<Mutex<(Vec<f32>, Vec<f32>)>>,
-    resampler: Arc<Mutex<FftFixedInOut<f32>>>,
-    resampler_input_frames_needed: usize,
-}
-
-impl EmittedSink {
-    fn new() -> EmittedSink {
-        // By setting the sync_channel bound to at least the output frame size of one resampling
-        // step (1120 for a chunk size of 1024 and our frequency settings) the number of
-        // synchronizations needed between EmittedSink::write and EmittedSink::read can be reduced.
-        let (sender, receiver) = sync_channel::<[f32; 2]>(1120);
-
-        let resampler = FftFixedInOut::<f32>::new(
-            librespot::playback::SAMPLE_RATE as usize,
-            songbird::constants::SAMPLE_RATE_RAW,
-            1024,
-            2,
-        )
-        .unwrap();
-
-        let resampler_input_frames_needed = resampler.input_frames_max();
-
-        EmittedSink {
-            sender: Arc::new(sender),
-            receiver: Arc::new(Mutex::new(receiver)),
-            input_buffer: Arc::new(Mutex::new((
-                Vec::with_capacity(resampler_input_frames_needed),
-                Vec::with_capacity(resampler_input_frames_needed),
-            ))),
-            resampler: Arc::new(Mutex::new(resampler)),
-            resampler_input_frames_needed,
-        }
-    }
-}
-
-impl audio_backend::Sink for EmittedSink {
-    fn start(&mut self) -> SinkResult<()> {
-        Ok(())
-    }
-
-    fn stop(&mut self) -> SinkResult<()> {
-        Ok(())
-    }
-
-    fn write(&mut self, packet: AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
-        let frames_needed = self.resampler_input_frames_needed;
-        let mut input_buffer = self.input_buffer.lock().unwrap();
-
-        let mut resampler = self.resampler.lock().unwrap();
-
-        let mut resampled_buffer = resampler.output_buffer_allocate();
-
-        for c in packet.samples().unwrap().chunks_exact(2) {
-            input_buffer.0.push(c[0] as f32);
-            input_buffer.1.push(c[1] as f32);
-            if input_buffer.0.len() == frames_needed {
-                resampler
-                    .process_into_buffer(
-                        &[
-                            &input_buffer.0[0..frames_needed],
-                            &input_buffer.1[0..frames_needed],
-                        ],
-                        &mut resampled_buffer,
-                        None,
-                    )
-                    .unwrap();
-
-                input_buffer.0.clear();
-                input_buffer.1.clear();
-
-                let sender = self.sender.clone();
-
-                for i in 0..resampled_buffer[0].len() {
-                    sender
-                        .send([resampled_buffer[0][i], resampled_buffer[1][i]])
-                        .unwrap()
-                }
-            }
-        }
-
-        Ok(())
-    }
-}
-
-impl io::Read for EmittedSink {
-    fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
-        let sample_size = mem::size_of::<f32>() * 2;
-
-        if buff.len() < sample_size {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "EmittedSink does not support read buffer too small to guarantee \
-                holding one audio sample (8 bytes)",
-            ));
-        }
-
-        let receiver = self.receiver.lock().unwrap();
-
-        let mut bytes_written = 0;
-        while bytes_written + (sample_size - 1) < buff.len() {
-            if bytes_written == 0 {
-                // We can not return 0 bytes because songbird then thinks that the track has ended,
-                // therefore block until at least one stereo data set can be returned.
-
-                let sample = receiver.recv().unwrap();
-                LittleEndian::write_f32_into(
-                    &sample,
-                    &mut buff[bytes_written..(bytes_written + sample_size)],
-                );
-            } else if let Ok(data) = receiver.try_recv() {
-                LittleEndian::write_f32_into(
-                    &data,
-                    &mut buff[bytes_written..(bytes_written + sample_size)],
-                );
-            } else {
-                break;
-            }
-            bytes_written += sample_size;
-        }
-
-        Ok(bytes_written)
-    }
-}
-
-impl io::Seek for EmittedSink {
-    fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> {
-        unreachable!()
-    }
-}
-
-impl MediaSource for EmittedSink {
-    fn is_seekable(&self) -> bool {
-        false
-    }
-
-    fn byte_len(&self) -> Option<u64> {
-        None
-    }
-}
-
-impl Clone for EmittedSink {
-    fn clone(&self) -> EmittedSink {
-        EmittedSink {
-            receiver: self.receiver.clone(),
-            sender: self.sender.clone(),
-            input_buffer: self.input_buffer.clone(),
-            resampler: self.resampler.clone(),
-            resampler_input_frames_needed: self.resampler_input_frames_needed,
-        }
-    }
-}
-
 pub struct SpotifyPlayerKey;
 
+/// One player per profile, keyed by profile name.
 impl TypeMapKey for SpotifyPlayerKey {
-    type Value = Arc<tokio::sync::Mutex<SpotifyPlayer>>;
+    type Value = HashMap<String, Arc<tokio::sync::Mutex<SpotifyPlayer>>>;
 }
 
 impl SpotifyPlayer {
     pub async fn new(
-        username: String,
-        password: String,
-        quality: Bitrate,
-        cache_dir: Option<String>,
-        bot_autoplay: bool,
-        device_name: String,
-    ) -> SpotifyPlayer {
-        let credentials = Credentials::with_password(username, password);
-
-        let session_config = SessionConfig::default();
-
-        // 4 GB
-        let mut cache_limit: u64 = 10;
-        cache_limit = cache_limit.pow(9);
-        cache_limit *= 4;
-
-        let cache = Cache::new(
-            cache_dir.clone(),
-            cache_dir.clone(),
-            cache_dir,
-            Some(cache_limit),
-        )
-        .ok();
-
-        let (session, _) = Session::connect(session_config, credentials, cache, false)
-            .await
-            .expect("Error creating session");
-
-        let player_config = PlayerConfig {
-            bitrate: quality,
-            ..Default::default()
+        profile: &Profile,
+        player_config: PlayerConfig,
+        mixer_config: MixerConfig,
+        audio_config: AudioConfig,
+        cache_paths: CachePaths,
+    ) -> Result<SpotifyPlayer> {
+        let cache = match Cache::new(
+            cache_paths.credentials,
+            cache_paths.volume,
+            cache_paths.audio,
+            Some(cache_paths.size_limit),
+        ) {
+            Ok(cache) => Some(cache),
+            Err(why) => {
+                warn!(profile = %profile.name, error = %why, "Could not open cache, continuing without it");
+                None
+            }
         };
 
-        let emitted_sink = EmittedSink::new();
+        let session = session::connect_with_backoff(profile, cache.clone()).await?;
+
+        let emitted_sink = EmittedSink::new(&audio_config)?;
 
         let cloned_sink = emitted_sink.clone();
 
-        let mixer = Box::new(SoftMixer::open(MixerConfig {
-            volume_ctrl: VolumeCtrl::Linear,
-            ..MixerConfig::default()
-        }));
+        let mixer = TrackMixer::open(mixer_config);
+
+        // Spirc saves every volume change to the cache, pick up where the last run left off
+        if let Some(volume) = cache.as_ref().and_then(Cache::volume) {
+            mixer.set_volume(volume);
+        }
 
         let (_player, rx) = Player::new(
             player_config.clone(),
             session.clone(),
-            mixer.get_soft_volume(),
+            Box::new(NoOpVolume),
             move || Box::new(cloned_sink),
         );
 
-        SpotifyPlayer {
+        Ok(SpotifyPlayer {
+            name: profile.name.clone(),
+            profile: profile.clone(),
+            cache,
+            guild_id: None,
+            broadcast_guilds: Vec::new(),
             player_config,
             emitted_sink,
             session,
             spirc: None,
-            event_channel: Some(Arc::new(tokio::sync::Mutex::new(rx))),
+            event_channel: Arc::new(tokio::sync::Mutex::new(rx)),
             mixer,
-            bot_autoplay,
-            device_name,
-        }
+            bot_autoplay: profile.spotify_bot_autoplay,
+            device_name: profile.spotify_device_name.clone(),
+        })
     }
 
     pub async fn enable_connect(&mut self) {
@@ -270,7 +115,7 @@ impl SpotifyPlayer {
             name: self.device_name.clone(),
             device_type: DeviceType::AudioDongle,
             initial_volume: None,
-            has_volume_ctrl: true,
+            has_volume_ctrl: self.mixer.has_volume_ctrl(),
             autoplay: self.bot_autoplay,
         };
 
@@ -279,13 +124,16 @@ impl SpotifyPlayer {
         let (player, player_events) = Player::new(
             self.player_config.clone(),
             self.session.clone(),
-            self.mixer.get_soft_volume(),
+            // The volume is applied by songbird, see `TrackMixer`
+            Box::new(NoOpVolume),
             move || Box::new(cloned_sink),
         );
+        self.emitted_sink.follow(player.get_player_event_channel());
 
         let cloned_session = self.session.clone();
 
-        let (spirc, task) = Spirc::new(config, cloned_session, player, self.mixer.clone());
+        let (spirc, task) =
+            Spirc::new(config, cloned_session, player, Box::new(self.mixer.clone()));
 
         let handle = tokio::runtime::Handle::current();
         handle.spawn(async {
@@ -294,15 +142,59 @@ impl SpotifyPlayer {
 
         self.spirc = Some(Box::new(spirc));
 
-        let mut channel_lock = self.event_channel.as_ref().unwrap().lock().await;
+        let mut channel_lock = self.event_channel.lock().await;
         *channel_lock = player_events;
     }
 
+    /// Applies new Connect and playback settings. If casting is enabled, the Connect device is
+    /// restarted so Spotify picks up the change.
+    pub async fn update_connect_settings(
+        &mut self,
+        device_name: String,
+        bot_autoplay: bool,
+        player_config: PlayerConfig,
+    ) {
+        self.device_name = device_name;
+        self.bot_autoplay = bot_autoplay;
+        self.player_config = player_config;
+
+        if self.is_connect_enabled() {
+            self.disable_connect().await;
+            self.enable_connect().await;
+        }
+    }
+
+    /// Whether Spotify dropped the session, see [`session::supervise`].
+    pub fn is_session_invalid(&self) -> bool {
+        self.session.is_invalid()
+    }
+
+    /// Profile and cache to connect a replacement session with.
+    pub fn reconnect_params(&self) -> (Profile, Option<Cache>) {
+        (self.profile.clone(), self.cache.clone())
+    }
+
+    /// Swaps in a new session. The Connect device is bound to the old one, so if casting is
+    /// enabled the player and Spirc are rebuilt and the device is advertised again.
+    pub async fn replace_session(&mut self, session: Session) {
+        self.session = session;
+
+        if self.is_connect_enabled() {
+            info!(profile = %self.name, "Re-advertising Spotify Connect device");
+            self.disable_connect().await;
+            self.enable_connect().await;
+        }
+    }
+
+    pub fn is_connect_enabled(&self) -> bool {
+        self.spirc.is_some()
+    }
+
     pub async fn disable_connect(&mut self) {
-        if let Some(spirc) = self.spirc.as_ref() {
+        if let Some(spirc) = self.spirc.take() {
             spirc.shutdown();
 
-            self.event_channel.as_ref().unwrap().lock().await.close();
+            self.event_channel.lock().await.close();
         }
     }
 }
@@ -0,0 +1,758 @@
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::PlayerEvent;
+
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use serenity::prelude::{RwLock, TypeMap};
+use songbird::constants::{DEFAULT_BITRATE, SAMPLE_RATE_RAW, STEREO_FRAME_SIZE};
+use songbird::driver::opus::{coder::Encoder as OpusEncoder, Application, Channels, SampleRate};
+
+use super::config::{ConfigKey, RecorderConfig, RecorderFormat};
+use super::player::SpotifyPlayerKey;
+use super::wav::{wav_header, PcmFormat};
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use tracing::{info, warn};
+
+/// Packets queued for the writing thread at most, several seconds of audio. If the disk can't
+/// keep up for that long, recording stops rather than the audio.
+const QUEUE_PACKETS: usize = 500;
+
+/// Writes the audio a sink plays to files, one per track.
+///
+/// Only splits the stream where tracks start and queues it, encoding and writing happens on a
+/// thread of its own so that it never holds up the audio.
+#[derive(Default)]
+pub struct Recorder {
+    writer: Option<Writer>,
+    /// Frames written so far, whether recording or not.
+    written: u64,
+    /// Where in the frames written the upcoming tracks start, `None` where playback stops.
+    boundaries: VecDeque<(u64, Option<SpotifyId>)>,
+    /// Track the audio written next belongs to.
+    track_id: Option<SpotifyId>,
+}
+
+/// Thread writing the files of one recording, and the commands queued for it.
+struct Writer {
+    config: RecorderConfig,
+    commands: SyncSender<Command>,
+    thread: JoinHandle<()>,
+}
+
+enum Command {
+    /// The audio that follows belongs to this track, or to none.
+    Track(Option<SpotifyId>),
+    Write(Vec<f32>),
+    Sync,
+    /// Artist and title of a track, once its metadata has been fetched.
+    Name(SpotifyId, String),
+}
+
+impl Recorder {
+    pub fn is_recording(&self) -> bool {
+        self.writer
+            .as_ref()
+            .is_some_and(|writer| !writer.thread.is_finished())
+    }
+
+    /// Starts recording with `config`, or stops with `None`. A recording carries on if its
+    /// settings are unchanged.
+    pub fn set(&mut self, config: Option<RecorderConfig>) {
+        let current = self
+            .writer
+            .as_ref()
+            .filter(|_| self.is_recording())
+            .map(|writer| &writer.config);
+        if current == config.as_ref() {
+            return;
+        }
+
+        // The thread finishes the file on its own once its queue is dropped
+        self.writer = None;
+
+        let Some(config) = config else {
+            return info!("Stopped recording");
+        };
+
+        info!(dir = %config.dir.display(), format = ?config.format, "Recording");
+        match Writer::spawn(config, self.track_id) {
+            Ok(writer) => self.writer = Some(writer),
+            Err(why) => warn!(error = %why, "Could not start recording"),
+        }
+    }
+
+    /// Follows the player's events, in step with the audio. `in_flight` is how many frames of
+    /// the packets written so far the sink still holds before they reach the recorder.
+    pub fn event(&mut self, event: &PlayerEvent, in_flight: usize) {
+        let boundary = match *event {
+            PlayerEvent::Started { track_id, .. }
+            | PlayerEvent::Changed {
+                new_track_id: track_id,
+                ..
+            } => (self.written + in_flight as u64, Some(track_id)),
+            // librespot stops the sink before it reports this, which writes out everything
+            PlayerEvent::Stopped { .. } => (self.written, None),
+            _ => return,
+        };
+
+        // What was still in flight for earlier tracks was dropped if this one starts sooner
+        for (position, _) in &mut self.boundaries {
+            *position = (*position).min(boundary.0);
+        }
+        self.boundaries.push_back(boundary);
+
+        self.cross_boundaries();
+    }
+
+    pub fn write(&mut self, mut samples: &[f32]) {
+        while !samples.is_empty() {
+            let until_boundary = self.boundaries.front().map_or(usize::MAX, |(position, _)| {
+                (position - self.written) as usize
+            });
+            let (now, later) =
+                samples.split_at(samples.len().min(until_boundary.saturating_mul(2)));
+
+            // Nothing is playing, this is at most silence the processors held back
+            if self.track_id.is_some() {
+                self.send(Command::Write(now.to_vec()));
+            }
+
+            self.written += now.len() as u64 / 2;
+            samples = later;
+            self.cross_boundaries();
+        }
+    }
+
+    /// Makes the file complete up to here. Called when playback stops or pauses.
+    pub fn sync(&mut self) {
+        self.send(Command::Sync);
+    }
+
+    /// Names the file of `track_id` after its artist and title, once its metadata has been
+    /// fetched.
+    pub fn name(&mut self, track_id: SpotifyId, artist: Option<&str>, title: &str) {
+        let name = match artist {
+            Some(artist) => format!("{} - {}", artist, title),
+            None => title.to_string(),
+        };
+        self.send(Command::Name(track_id, name));
+    }
+
+    /// Moves on to the tracks starting at the frame written next.
+    fn cross_boundaries(&mut self) {
+        while let Some(&(_, track_id)) = self
+            .boundaries
+            .front()
+            .filter(|(position, _)| *position <= self.written)
+        {
+            self.boundaries.pop_front();
+            self.track_id = track_id;
+            self.send(Command::Track(track_id));
+        }
+    }
+
+    fn send(&mut self, command: Command) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+
+        match writer.commands.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("Could not write recordings fast enough, stopped recording");
+                self.writer = None;
+            }
+            // The thread gave up and told why
+            Err(TrySendError::Disconnected(_)) => self.writer = None,
+        }
+    }
+
+    /// Stops recording and waits until the files are written.
+    #[cfg(test)]
+    pub fn join(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            drop(writer.commands);
+            writer.thread.join().unwrap();
+        }
+    }
+}
+
+impl Writer {
+    fn spawn(config: RecorderConfig, track_id: Option<SpotifyId>) -> io::Result<Writer> {
+        let (commands, queue) = sync_channel(QUEUE_PACKETS);
+
+        let mut files = Files {
+            config: config.clone(),
+            track_id,
+            name: None,
+            recording: None,
+        };
+        let thread = thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || files.run(queue))?;
+
+        Ok(Writer {
+            config,
+            commands,
+            thread,
+        })
+    }
+}
+
+/// The recorder's thread, writing the file of each track.
+struct Files {
+    config: RecorderConfig,
+    track_id: Option<SpotifyId>,
+    /// Latest track title, which may arrive before the track's audio.
+    name: Option<(SpotifyId, String)>,
+    recording: Option<Recording>,
+}
+
+/// File of the track being recorded.
+struct Recording {
+    encoder: Box<dyn Encoder>,
+    path: PathBuf,
+    track_id: SpotifyId,
+    format: RecorderFormat,
+    /// Seconds since the epoch when the file was created, file names start with it so they
+    /// sort in playing order.
+    started: u64,
+    /// Whether the file is named after the track yet, rather than its ID.
+    named: bool,
+}
+
+impl Files {
+    /// Handles commands until the recorder stops, or gives up once writing fails since it
+    /// would most likely keep failing.
+    fn run(&mut self, queue: Receiver<Command>) {
+        for command in queue {
+            if let Err(why) = self.handle(command) {
+                return warn!(error = %why, "Could not record, stopped recording");
+            }
+        }
+
+        self.finish();
+    }
+
+    fn handle(&mut self, command: Command) -> io::Result<()> {
+        match command {
+            Command::Track(track_id) => {
+                self.finish();
+                self.track_id = track_id;
+            }
+            Command::Write(samples) => {
+                let Some(track_id) = self.track_id else {
+                    return Ok(());
+                };
+
+                if self.recording.is_none() {
+                    let name = self
+                        .name
+                        .as_ref()
+                        .filter(|(id, _)| *id == track_id)
+                        .map(|(_, name)| name.as_str());
+                    self.recording = Some(Recording::create(&self.config, track_id, name)?);
+                }
+
+                if let Some(recording) = &mut self.recording {
+                    recording.encoder.write(&samples)?;
+                }
+            }
+            Command::Sync => {
+                if let Some(recording) = &mut self.recording {
+                    recording.encoder.sync()?;
+                }
+            }
+            Command::Name(track_id, name) => {
+                let name = sanitize(&name);
+                if let Some(recording) = &mut self.recording {
+                    recording.name(track_id, &name);
+                }
+                self.name = Some((track_id, name));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            let path = recording.path;
+            match recording.encoder.finish() {
+                Ok(()) => info!(path = %path.display(), "Recorded track"),
+                Err(why) => {
+                    warn!(path = %path.display(), error = %why, "Could not finish recording")
+                }
+            }
+        }
+    }
+}
+
+impl Recording {
+    /// Creates the file of `track_id`, named after the track if its `name` is known already.
+    fn create(
+        config: &RecorderConfig,
+        track_id: SpotifyId,
+        name: Option<&str>,
+    ) -> io::Result<Recording> {
+        fs::create_dir_all(&config.dir)?;
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // Named after the track's ID until its metadata arrives
+        let id = track_id
+            .to_base62()
+            .unwrap_or_else(|_| "unknown".to_string());
+        let name = name.filter(|name| !name.is_empty());
+        let (file, path) = create_file(&config.dir, started, name.unwrap_or(&id), config.format)?;
+
+        let file = BufWriter::new(file);
+        let encoder: Box<dyn Encoder> = match config.format {
+            RecorderFormat::Wav => Box::new(Wav::new(file)?),
+            RecorderFormat::Opus => Box::new(OggOpus::new(file)?),
+        };
+
+        Ok(Recording {
+            encoder,
+            path,
+            track_id,
+            format: config.format,
+            started,
+            named: name.is_some(),
+        })
+    }
+
+    /// Renames the file after the artist and title of `track_id`, if it is that track's.
+    fn name(&mut self, track_id: SpotifyId, name: &str) {
+        if self.named || self.track_id != track_id || name.is_empty() {
+            return;
+        }
+
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let path = (1..)
+            .map(|number| dir.join(file_name(self.started, name, number, self.format)))
+            .find(|path| !path.exists())
+            .unwrap();
+
+        // Keep the ID as the name if renaming fails, the recording itself is fine
+        match fs::rename(&self.path, &path) {
+            Ok(()) => {
+                self.path = path;
+                self.named = true;
+            }
+            Err(why) => warn!(error = %why, "Could not rename recording"),
+        }
+    }
+}
+
+/// Creates a file named after `name` in `dir`. If a recording of the same name was started in
+/// the same second, like a track played again right away, the new one is numbered.
+fn create_file(
+    dir: &Path,
+    started: u64,
+    name: &str,
+    format: RecorderFormat,
+) -> io::Result<(File, PathBuf)> {
+    for number in 1.. {
+        let path = dir.join(file_name(started, name, number, format));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(why) if why.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(why) => return Err(why),
+        }
+    }
+
+    unreachable!()
+}
+
+fn file_name(started: u64, name: &str, number: u32, format: RecorderFormat) -> String {
+    let extension = match format {
+        RecorderFormat::Wav => "wav",
+        RecorderFormat::Opus => "opus",
+    };
+
+    if number == 1 {
+        format!("{}-{}.{}", started, name, extension)
+    } else {
+        format!("{}-{} ({}).{}", started, name, number, extension)
+    }
+}
+
+/// Replaces what file systems don't allow or would misread in a track name. Windows drops
+/// trailing dots and spaces, which could make two names the same.
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(100)
+        .collect();
+
+    name.trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string()
+}
+
+/// Toggles recording for every player when the process receives SIGUSR1, into the configured
+/// directory and format. This holds until a config reload changes the recorder settings.
+pub fn spawn_toggle(data: Arc<RwLock<TypeMap>>) {
+    #[cfg(unix)]
+    tokio::spawn(watch_user_signal(data));
+
+    #[cfg(not(unix))]
+    let _ = data;
+}
+
+#[cfg(unix)]
+async fn watch_user_signal(data: Arc<RwLock<TypeMap>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_signal = match signal(SignalKind::user_defined1()) {
+        Ok(user_signal) => user_signal,
+        Err(why) => {
+            warn!(error = %why, "Could not listen for SIGUSR1");
+            return;
+        }
+    };
+
+    while user_signal.recv().await.is_some() {
+        let (config, players) = {
+            let data = data.read().await;
+            (
+                data.get::<ConfigKey>().unwrap().clone(),
+                data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            )
+        };
+
+        for player in players.values() {
+            let player = player.lock().await;
+            let recording = player.emitted_sink.is_recording();
+
+            info!(
+                profile = %player.name,
+                "Received SIGUSR1, {} recording",
+                if recording { "stopping" } else { "starting" }
+            );
+
+            player
+                .emitted_sink
+                .set_recording((!recording).then(|| config.recorder_config(&player.name)));
+        }
+    }
+}
+
+/// Writes interleaved stereo samples at 48 kHz to a file.
+trait Encoder: Send {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+
+    /// Makes the file readable up to what was written so far.
+    fn sync(&mut self) -> io::Result<()>;
+
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// 32-bit float WAV, exactly the samples played.
+struct Wav {
+    file: BufWriter<File>,
+    frames: u64,
+}
+
+impl Wav {
+    fn new(mut file: BufWriter<File>) -> io::Result<Wav> {
+        file.write_all(&wav_header(PcmFormat::F32, 0))?;
+        Ok(Wav { file, frames: 0 })
+    }
+}
+
+impl Encoder for Wav {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_f32::<LittleEndian>(*sample)?;
+        }
+
+        self.frames += samples.len() as u64 / 2;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file
+            .write_all(&wav_header(PcmFormat::F32, self.frames))?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+/// Opus in an Ogg container, encoded like songbird encodes for Discord, so that it sounds like
+/// what listeners hear.
+struct OggOpus {
+    writer: PacketWriter<BufWriter<File>>,
+    encoder: OpusEncoder,
+    serial: u32,
+    /// Samples the decoder drops at the start, the encoder's lookahead.
+    pre_skip: u64,
+    /// Interleaved samples of the Opus frame being collected.
+    frame: Vec<f32>,
+    /// Frames encoded so far, at 48 kHz.
+    frames: u64,
+    /// Last packet and its granule position. Held back so that it can end a page when syncing,
+    /// or the stream when finishing.
+    held: Option<(Box<[u8]>, u64)>,
+}
+
+impl OggOpus {
+    fn new(file: BufWriter<File>) -> io::Result<OggOpus> {
+        let mut encoder =
+            OpusEncoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+                .map_err(io::Error::other)?;
+        encoder
+            .set_bitrate(DEFAULT_BITRATE)
+            .map_err(io::Error::other)?;
+        let pre_skip = encoder.lookahead().map_err(io::Error::other)? as u64;
+
+        // Any serial will do for a single stream, vary it so that files can be chained
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+
+        let mut writer = PacketWriter::new(file);
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(2);
+        head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&(SAMPLE_RATE_RAW as u32).to_le_bytes());
+        // No output gain, channel mapping family 0
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        writer.write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        let vendor = b"aoede";
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor);
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(OggOpus {
+            writer,
+            encoder,
+            serial,
+            pre_skip,
+            frame: Vec::with_capacity(STEREO_FRAME_SIZE),
+            frames: 0,
+            held: None,
+        })
+    }
+
+    /// Encodes the collected frame, padded with silence, covering `frames` of audio.
+    fn encode(&mut self, frames: u64) -> io::Result<Box<[u8]>> {
+        self.frame.resize(STEREO_FRAME_SIZE, 0.0);
+
+        let mut packet = [0; 4000];
+        let len = self
+            .encoder
+            .encode_float(&self.frame, &mut packet)
+            .map_err(io::Error::other)?;
+
+        self.frame.clear();
+        self.frames += frames;
+
+        Ok(packet[..len].into())
+    }
+
+    fn write_held(&mut self, end: PacketWriteEndInfo) -> io::Result<()> {
+        if let Some((packet, granule)) = self.held.take() {
+            self.writer
+                .write_packet(packet, self.serial, end, granule)?;
+        }
+        Ok(())
+    }
+}
+
+impl Encoder for OggOpus {
+    fn write(&mut self, mut samples: &[f32]) -> io::Result<()> {
+        while !samples.is_empty() {
+            let taken = (STEREO_FRAME_SIZE - self.frame.len()).min(samples.len());
+            self.frame.extend_from_slice(&samples[..taken]);
+            samples = &samples[taken..];
+
+            if self.frame.len() == STEREO_FRAME_SIZE {
+                let packet = self.encode(STEREO_FRAME_SIZE as u64 / 2)?;
+                self.write_held(PacketWriteEndInfo::NormalPacket)?;
+                self.held = Some((packet, self.pre_skip + self.frames));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.write_held(PacketWriteEndInfo::EndPage)?;
+        self.writer.inner_mut().flush()
+    }
+
+    /// The last packet is padded to a whole frame, its granule position tells the decoder
+    /// where the audio ends.
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        if !self.frame.is_empty() || self.held.is_none() {
+            let packet = self.encode(self.frame.len() as u64 / 2)?;
+            self.write_held(PacketWriteEndInfo::NormalPacket)?;
+            self.held = Some((packet, self.pre_skip + self.frames));
+        }
+
+        self.write_held(PacketWriteEndInfo::EndStream)?;
+
+        self.writer.inner_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use songbird::driver::opus::{coder::Decoder as OpusDecoder, MutSignals};
+    use std::convert::TryInto;
+    use std::f32::consts::PI;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("aoede-{}-{}", std::process::id(), name))
+    }
+
+    fn sine(hz: f32, amplitude: f32, frames: usize) -> impl Iterator<Item = f32> {
+        (0..frames).flat_map(move |i| {
+            let phase = 2.0 * PI * hz * i as f32 / SAMPLE_RATE_RAW as f32;
+            [amplitude * phase.sin(), amplitude * phase.cos()]
+        })
+    }
+
+    /// Writes `samples` in packets of an uneven size, syncing halfway like a pause does.
+    fn encode(mut encoder: Box<dyn Encoder>, samples: &[f32]) {
+        let packets: Vec<&[f32]> = samples.chunks(2 * 1117).collect();
+
+        for (i, packet) in packets.iter().enumerate() {
+            encoder.write(packet).unwrap();
+            if i == packets.len() / 2 {
+                encoder.sync().unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(sanitize("AC/DC - Back In Black"), "AC_DC - Back In Black");
+        assert_eq!(sanitize("What? <Live> \"Remix\""), "What_ _Live_ _Remix_");
+        assert_eq!(sanitize("Tab\there\n"), "Tab_here_");
+        assert_eq!(sanitize("..Hidden. . "), "Hidden");
+        assert_eq!(sanitize(" \u{1F3B5} Ünïcödé "), "\u{1F3B5} Ünïcödé");
+        assert_eq!(sanitize(&"x".repeat(300)).len(), 100);
+        assert_eq!(sanitize("..."), "");
+    }
+
+    #[test]
+    fn files_of_the_same_name_are_numbered() {
+        let dir = temp_path("collisions");
+        fs::create_dir_all(&dir).unwrap();
+
+        let (_, first) = create_file(&dir, 1, "Artist - Song", RecorderFormat::Wav).unwrap();
+        let (_, second) = create_file(&dir, 1, "Artist - Song", RecorderFormat::Wav).unwrap();
+        let (_, other) = create_file(&dir, 2, "Artist - Song", RecorderFormat::Wav).unwrap();
+        assert_eq!(first, dir.join("1-Artist - Song.wav"));
+        assert_eq!(second, dir.join("1-Artist - Song (2).wav"));
+        assert_eq!(other, dir.join("2-Artist - Song.wav"));
+
+        // Renaming after the metadata arrives doesn't replace either of them
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let (file, path) = create_file(&dir, 1, "4uLU6hMCjMI75M1A2tKUQC", RecorderFormat::Wav)
+            .map(|(file, path)| (BufWriter::new(file), path))
+            .unwrap();
+        let mut recording = Recording {
+            encoder: Box::new(Wav::new(file).unwrap()),
+            path,
+            track_id,
+            format: RecorderFormat::Wav,
+            started: 1,
+            named: false,
+        };
+        recording.name(track_id, "Artist - Song");
+        assert_eq!(recording.path, dir.join("1-Artist - Song (3).wav"));
+        assert!(first.exists() && second.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ogg_opus_decodes_to_the_samples_written() {
+        // Not a whole number of Opus frames, so the last one is padded
+        let frames = SAMPLE_RATE_RAW + 300;
+        let samples: Vec<f32> = sine(440.0, 0.5, frames).collect();
+
+        let path = temp_path("decodes.opus");
+        let file = BufWriter::new(File::create(&path).unwrap());
+        encode(Box::new(OggOpus::new(file).unwrap()), &samples);
+
+        let mut reader = ogg::reading::PacketReader::new(File::open(&path).unwrap());
+
+        let head = reader.read_packet_expected().unwrap().data;
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[9], 2);
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]) as usize;
+        assert_eq!(&head[12..16], &(SAMPLE_RATE_RAW as u32).to_le_bytes());
+        let tags = reader.read_packet_expected().unwrap().data;
+        assert_eq!(&tags[..8], b"OpusTags");
+
+        let mut decoder = OpusDecoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        let mut decoded = Vec::new();
+        let mut end = None;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let mut output = vec![0.0; STEREO_FRAME_SIZE];
+            let output_signals: MutSignals<'_, f32> = (&mut output[..]).try_into().unwrap();
+            let len = decoder
+                .decode_float(
+                    Some(packet.data[..].try_into().unwrap()),
+                    output_signals,
+                    false,
+                )
+                .unwrap();
+            decoded.extend_from_slice(&output[..len * 2]);
+            end = Some((packet.absgp_page(), packet.last_in_stream()));
+        }
+        fs::remove_file(&path).unwrap();
+
+        // The granule position of the last page tells where the audio ends
+        assert_eq!(end, Some(((pre_skip + frames) as u64, true)));
+
+        // Without the encoder's lookahead, the decoded audio lines up with what was written
+        let decoded = &decoded[pre_skip * 2..(pre_skip + frames) * 2];
+        let signal: f32 = samples.iter().map(|sample| sample * sample).sum();
+        let noise: f32 = samples
+            .iter()
+            .zip(decoded)
+            .map(|(sample, decoded)| (sample - decoded).powi(2))
+            .sum();
+        let snr_db = 10.0 * (signal / noise).log10();
+        assert!(snr_db > 30.0, "SNR of {:.1} dB", snr_db);
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serenity::prelude::{RwLock, TypeMap};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+use super::config::{Config, ConfigKey, CONFIG_PATH};
+use super::player::SpotifyPlayerKey;
+
+/// Reloads the configuration whenever `config.toml` changes on disk or the process receives
+/// SIGHUP. Settings that can be applied live take effect immediately, the others are kept
+/// until Aoede is restarted. The names of profiles whose followed users changed are sent to
+/// `refollow`, so they are looked for in voice again.
+pub fn spawn(data: Arc<RwLock<TypeMap>>, refollow: UnboundedSender<String>) {
+    tokio::spawn(watch_file(data.clone(), refollow.clone()));
+
+    #[cfg(unix)]
+    tokio::spawn(watch_hangup(data, refollow));
+}
+
+fn modified() -> Option<SystemTime> {
+    std::fs::metadata(CONFIG_PATH)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+async fn watch_file(data: Arc<RwLock<TypeMap>>, refollow: UnboundedSender<String>) {
+    let mut last_modified = modified();
+    let mut poll = interval(Duration::from_secs(2));
+
+    loop {
+        poll.tick().await;
+
+        let current = modified();
+        if current != last_modified {
+            last_modified = current;
+            info!("{} changed, reloading config", CONFIG_PATH);
+            reload(&data, &refollow).await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn watch_hangup(data: Arc<RwLock<TypeMap>>, refollow: UnboundedSender<String>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(why) => {
+            warn!(error = %why, "Could not listen for SIGHUP");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config");
+        reload(&data, &refollow).await;
+    }
+}
+
+async fn reload(data: &RwLock<TypeMap>, refollow: &UnboundedSender<String>) {
+    let config = match Config::new() {
+        Ok(config) => config,
+        Err(error) => {
+            warn!(error = %error, "Couldn't reload config, keeping the current one");
+            return;
+        }
+    };
+
+    let mut data = data.write().await;
+
+    let old = data.get::<ConfigKey>().unwrap().clone();
+    let changes = old.changes(&config);
+    if changes.is_empty() {
+        info!("Config reloaded, nothing changed");
+        return;
+    }
+
+    for change in &changes {
+        if change.requires_restart {
+            warn!(
+                setting = change.field,
+                "Setting changed, restart Aoede to apply it"
+            );
+        } else {
+            info!(setting = change.field, "Setting changed, applying it now");
+        }
+    }
+
+    // The playback settings are shared by the players of every profile
+    let player_config_changed = old.player_config_changed(&config);
+
+    let players = data.get::<SpotifyPlayerKey>().unwrap().clone();
+    let player_config = config.player_config();
+    let guild_changed = changes.iter().any(|change| change.field == "guild");
+    let recorder_changed = changes
+        .iter()
+        .any(|change| change.field.starts_with("recorder_"));
+
+    data.insert::<ConfigKey>(config.clone());
+    drop(data);
+
+    if guild_changed {
+        // Effects are swapped in while playing, the other guild settings are read when joining
+        for player in players.values() {
+            let player = player.lock().await;
+            if let Some(guild_id) = player.guild_id {
+                player
+                    .emitted_sink
+                    .set_effects(&config.guild(guild_id.0).effects);
+            }
+            for (guild_id, sink) in &player.broadcast_guilds {
+                sink.set_effects(&config.guild(guild_id.0).effects);
+            }
+        }
+    }
+
+    if recorder_changed {
+        for player in players.values() {
+            let player = player.lock().await;
+            player
+                .emitted_sink
+                .set_recording(config.recording(&player.name));
+        }
+    }
+
+    for profile in config.profiles() {
+        // Added profiles only get a player after a restart
+        let Some(player) = players.get(&profile.name) else {
+            continue;
+        };
+
+        // Broadcast channels are read whenever they are needed, only restart the Connect devices
+        // whose settings changed
+        let device_changed = old.profile(&profile.name).is_none_or(|old| {
+            old.spotify_device_name != profile.spotify_device_name
+                || old.spotify_bot_autoplay != profile.spotify_bot_autoplay
+        });
+
+        // The followed users may already be in voice, they won't be noticed until they move
+        let followed_changed = old
+            .profile(&profile.name)
+            .is_none_or(|old| old.discord_user_ids != profile.discord_user_ids);
+
+        if player_config_changed || device_changed {
+            info!(profile = %profile.name, "Applying new playback and Connect settings");
+            player
+                .lock()
+                .await
+                .update_connect_settings(
+                    profile.spotify_device_name,
+                    profile.spotify_bot_autoplay,
+                    player_config.clone(),
+                )
+                .await;
+        }
+
+        if followed_changed {
+            let _ = refollow.send(profile.name);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use rubato::{
+    FftFixedInOut, InterpolationParameters, InterpolationType, ResampleError, SincFixedIn,
+    VecResampler, WindowFunction,
+};
+
+use super::config::{ResamplerEngine, ResamplerQuality};
+use super::error::{AoedeError, Result};
+
+const INPUT_RATE: usize = librespot::playback::SAMPLE_RATE as usize;
+const OUTPUT_RATE: usize = songbird::constants::SAMPLE_RATE_RAW;
+
+/// Smallest number of input frames that resample to a whole number of output frames, and that
+/// number.
+const CYCLE_IN: usize = 147;
+const CYCLE_OUT: usize = 160;
+
+/// Resamples a stereo stream from librespot's 44.1 kHz to songbird's 48 kHz, collecting input
+/// until a full chunk can be processed.
+///
+/// The output is aligned with the input: the resampler's filter delay is trimmed from the start
+/// of a stream, and [`StreamResampler::finish`] gives out the tail it still holds at the end.
+/// Output frame `n` is the input at `n * 44100 / 48000` input frames.
+pub struct StreamResampler {
+    engine: ResamplerEngine,
+    quality: ResamplerQuality,
+    resampler: Box<dyn VecResampler<f32>>,
+    input: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+    interleaved: Vec<f32>,
+    /// Output frames still to drop at the start of the stream.
+    skip: usize,
+    /// Frames of silence put before the stream, part of `frames_in` and the collected input but
+    /// not of the stream.
+    lead: u64,
+    /// Frames taken in and given out since the stream started.
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl StreamResampler {
+    pub fn new(engine: ResamplerEngine, quality: ResamplerQuality) -> Result<StreamResampler> {
+        let (resampler, Alignment { lead, skip }) = match engine {
+            ResamplerEngine::Fft => fft(quality),
+            ResamplerEngine::Sinc => sinc(quality),
+        }
+        .map_err(|why| AoedeError::Sink(format!("could not create resampler: {}", why)))?;
+
+        let chunk = resampler.input_frames_max();
+        let output = resampler.output_buffer_allocate();
+
+        let mut input = vec![Vec::with_capacity(chunk), Vec::with_capacity(chunk)];
+        for channel in &mut input {
+            channel.resize(lead, 0.0);
+        }
+
+        Ok(StreamResampler {
+            engine,
+            quality,
+            input,
+            interleaved: Vec::with_capacity(resampler.output_frames_max() * 2),
+            resampler,
+            output,
+            skip,
+            lead: lead as u64,
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Adds one stereo frame. Once a chunk is complete it is resampled and the interleaved
+    /// output returned.
+    pub fn push(&mut self, left: f32, right: f32) -> Result<Option<&[f32]>, ResampleError> {
+        self.input[0].push(left);
+        self.input[1].push(right);
+
+        if self.input[0].len() < self.resampler.input_frames_next() {
+            return Ok(None);
+        }
+
+        self.process(usize::MAX).map(Some)
+    }
+
+    /// Resamples what is left of the stream, padding it with silence, and calls `emit` with the
+    /// interleaved output up to the stream's exact resampled length. The resampler is reset
+    /// afterwards, ready for an unrelated stream.
+    pub fn finish(&mut self, mut emit: impl FnMut(&[f32])) -> Result<()> {
+        if self.frames_in() == 0 {
+            return Ok(());
+        }
+
+        while self.pending() > 0 {
+            let output = self
+                .process(self.pending())
+                .map_err(|why| AoedeError::Sink(format!("could not resample: {}", why)))?;
+            emit(output);
+        }
+
+        self.reset()
+    }
+
+    /// Output frames still owed for the input taken so far, held in the collected input and the
+    /// filter delay.
+    pub fn pending(&self) -> usize {
+        let expected = (self.frames_in() * OUTPUT_RATE as u64).div_ceil(INPUT_RATE as u64);
+        expected.saturating_sub(self.frames_out) as usize
+    }
+
+    /// Frames of the stream taken in so far, collected or resampled.
+    fn frames_in(&self) -> u64 {
+        self.frames_in + self.input[0].len() as u64 - self.lead
+    }
+
+    /// Drops all input and filter state.
+    pub fn reset(&mut self) -> Result<()> {
+        *self = StreamResampler::new(self.engine, self.quality)?;
+        Ok(())
+    }
+
+    /// Resamples the collected input, padded to a full chunk, and returns at most `limit` frames
+    /// of output.
+    fn process(&mut self, limit: usize) -> Result<&[f32], ResampleError> {
+        let chunk = self.resampler.input_frames_next();
+
+        self.frames_in += self.input[0].len() as u64;
+        for channel in &mut self.input {
+            channel.resize(chunk, 0.0);
+        }
+
+        self.resampler
+            .process_into_buffer(&self.input, &mut self.output, None)?;
+
+        for channel in &mut self.input {
+            channel.clear();
+        }
+
+        let skip = self.skip.min(self.output[0].len());
+        self.skip -= skip;
+
+        let frames = (self.output[0].len() - skip).min(limit);
+
+        self.interleaved.clear();
+        for (left, right) in self.output[0][skip..skip + frames]
+            .iter()
+            .zip(&self.output[1][skip..skip + frames])
+        {
+            self.interleaved.extend_from_slice(&[*left, *right]);
+        }
+
+        self.frames_out += frames as u64;
+
+        Ok(&self.interleaved)
+    }
+}
+
+/// What it takes to align a resampler's output with its input.
+struct Alignment {
+    /// Frames of silence to put before the input.
+    lead: usize,
+    /// Frames to drop from the start of the output.
+    skip: usize,
+}
+
+type Constructed = std::result::Result<(Box<dyn VecResampler<f32>>, Alignment), String>;
+
+/// Synchronous FFT resampler. Larger chunks make for a longer, steeper anti-aliasing filter at
+/// the cost of latency.
+fn fft(quality: ResamplerQuality) -> Constructed {
+    // Chunks are even multiples of a cycle, which keeps the filter delay a whole number of frames
+    let chunk = match quality {
+        ResamplerQuality::Fast => 2 * CYCLE_IN,
+        ResamplerQuality::Balanced => 8 * CYCLE_IN,
+        ResamplerQuality::Best => 26 * CYCLE_IN,
+    };
+
+    let resampler = FftFixedInOut::<f32>::new(INPUT_RATE, OUTPUT_RATE, chunk, 2)
+        .map_err(|why| why.to_string())?;
+
+    // The anti-aliasing filter is as long as a chunk and centered on its middle, which delays
+    // the signal by half a chunk
+    let delay = VecResampler::output_frames_max(&resampler) / 2;
+
+    Ok((
+        Box::new(resampler),
+        Alignment {
+            lead: 0,
+            skip: delay,
+        },
+    ))
+}
+
+/// Steps between two input frames the sinc filter is tabulated at. A divisor of `CYCLE_OUT`, so
+/// that the output can be aligned exactly, see [`sinc_alignment`].
+const SINC_OVERSAMPLING: usize = 160;
+
+/// Asynchronous sinc interpolation. Longer filters and cubic interpolation reduce aliasing and
+/// high frequency roll-off, at a much higher CPU cost than the FFT resampler.
+fn sinc(quality: ResamplerQuality) -> Constructed {
+    let parameters = match quality {
+        ResamplerQuality::Fast => InterpolationParameters {
+            sinc_len: 64,
+            f_cutoff: 0.91,
+            interpolation: InterpolationType::Linear,
+            oversampling_factor: SINC_OVERSAMPLING,
+            window: WindowFunction::Hann2,
+        },
+        ResamplerQuality::Balanced => InterpolationParameters {
+            sinc_len: 128,
+            f_cutoff: 0.925,
+            interpolation: InterpolationType::Linear,
+            oversampling_factor: SINC_OVERSAMPLING,
+            window: WindowFunction::Blackman2,
+        },
+        ResamplerQuality::Best => InterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            interpolation: InterpolationType::Cubic,
+            oversampling_factor: SINC_OVERSAMPLING,
+            window: WindowFunction::BlackmanHarris2,
+        },
+    };
+
+    let resampler = SincFixedIn::<f32>::new(
+        OUTPUT_RATE as f64 / INPUT_RATE as f64,
+        1.0,
+        parameters,
+        512,
+        2,
+    )
+    .map_err(|why| why.to_string())?;
+
+    Ok((Box::new(resampler), sinc_alignment()?))
+}
+
+/// Rubato starts interpolating half a filter before the first input frame, which centers the
+/// filter on it, but its output frame `n` lands on input frame `(n + 1) * 147 / 160 - 1 + 1 /
+/// SINC_OVERSAMPLING`, a fraction of a frame off. Leading silence and skipped output shift that
+/// onto whole output frames.
+fn sinc_alignment() -> std::result::Result<Alignment, String> {
+    // Where output frame `skip` lands, in units fine enough for both the cycle and the filter
+    // steps to be whole
+    let units_per_frame = (CYCLE_OUT * SINC_OVERSAMPLING) as isize;
+    let lands = |skip: usize| {
+        ((skip + 1) * CYCLE_IN * SINC_OVERSAMPLING + CYCLE_OUT) as isize - units_per_frame
+    };
+
+    (0..CYCLE_OUT)
+        .find(|skip| lands(*skip) >= 0 && lands(*skip) % units_per_frame == 0)
+        .map(|skip| Alignment {
+            lead: (lands(skip) / units_per_frame) as usize,
+            skip,
+        })
+        .ok_or_else(|| "sinc oversampling must divide the resampling cycle".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::f64::consts::PI;
+
+    const SECONDS: f64 = 2.0;
+    /// Kept below the lowest cutoff of any preset.
+    const SWEEP_FROM_HZ: f64 = 50.0;
+    const SWEEP_TO_HZ: f64 = 16_000.0;
+    /// Lags searched for the best match, well beyond the longest filter's half length.
+    const MAX_LAG: isize = 256;
+    /// A fraction of a frame off already costs more than this at the top of the sweep.
+    const MIN_SNR_DB: f64 = 100.0;
+
+    /// Exponential sine sweep at `time` seconds, faded in and out so that its start and end are
+    /// band limited too.
+    fn sweep(time: f64) -> f64 {
+        let rate = (SWEEP_TO_HZ / SWEEP_FROM_HZ).ln() / SECONDS;
+        let phase = 2.0 * PI * SWEEP_FROM_HZ * ((rate * time).exp() - 1.0) / rate;
+
+        let fade = 0.05;
+        let gain = (time / fade).min((SECONDS - time) / fade).clamp(0.0, 1.0);
+        let gain = (1.0 - (PI * gain).cos()) / 2.0;
+
+        0.5 * gain * phase.sin()
+    }
+
+    /// Resamples the sweep frame by frame, the way the sink does, with the right channel
+    /// inverted.
+    fn resample(engine: ResamplerEngine, quality: ResamplerQuality) -> (Vec<f32>, usize) {
+        let mut resampler = StreamResampler::new(engine, quality).unwrap();
+        let frames = (SECONDS * INPUT_RATE as f64) as usize;
+        let mut output = Vec::new();
+
+        for i in 0..frames {
+            let sample = sweep(i as f64 / INPUT_RATE as f64) as f32;
+            if let Some(resampled) = resampler.push(sample, -sample).unwrap() {
+                output.extend_from_slice(resampled);
+            }
+        }
+        resampler
+            .finish(|resampled| output.extend_from_slice(resampled))
+            .unwrap();
+
+        (output, frames)
+    }
+
+    /// Correlation of the left channel `lag` frames late with the ideal sweep, over the middle
+    /// of the sweep where its frequency is highest.
+    fn correlation(left: &[f64], ideal: &[f64], lag: isize) -> f64 {
+        let middle = ideal.len() / 2..ideal.len() * 3 / 4;
+        middle
+            .map(|i| {
+                let lagged = usize::try_from(i as isize + lag).unwrap();
+                left[lagged] * ideal[i]
+            })
+            .sum()
+    }
+
+    /// Compares the resampled sweep with the sweep computed at 48 kHz.
+    fn check(engine: ResamplerEngine, quality: ResamplerQuality) {
+        let (output, frames) = resample(engine, quality);
+
+        let expected = (frames * OUTPUT_RATE).div_ceil(INPUT_RATE);
+        assert_eq!(output.len(), expected * 2, "{:?} {:?}", engine, quality);
+
+        let ideal: Vec<f64> = (0..expected)
+            .map(|i| sweep(i as f64 / OUTPUT_RATE as f64))
+            .collect();
+        let left: Vec<f64> = output
+            .chunks_exact(2)
+            .map(|frame| frame[0] as f64)
+            .collect();
+        assert!(output.chunks_exact(2).all(|frame| frame[0] == -frame[1]));
+
+        let lag = (-MAX_LAG..=MAX_LAG)
+            .max_by(|a, b| {
+                correlation(&left, &ideal, *a).total_cmp(&correlation(&left, &ideal, *b))
+            })
+            .unwrap();
+        assert_eq!(lag, 0, "{:?} {:?}", engine, quality);
+
+        let signal: f64 = ideal.iter().map(|sample| sample * sample).sum();
+        let noise: f64 = left
+            .iter()
+            .zip(&ideal)
+            .map(|(sample, ideal)| (sample - ideal) * (sample - ideal))
+            .sum();
+        let snr_db = 10.0 * (signal / noise).log10();
+
+        assert!(
+            snr_db >= MIN_SNR_DB,
+            "{:?} {:?}: {:.1} dB",
+            engine,
+            quality,
+            snr_db
+        );
+    }
+
+    #[test]
+    fn fft_fast() {
+        check(ResamplerEngine::Fft, ResamplerQuality::Fast);
+    }
+
+    #[test]
+    fn fft_balanced() {
+        check(ResamplerEngine::Fft, ResamplerQuality::Balanced);
+    }
+
+    #[test]
+    fn fft_best() {
+        check(ResamplerEngine::Fft, ResamplerQuality::Best);
+    }
+
+    #[test]
+    fn sinc_fast() {
+        check(ResamplerEngine::Sinc, ResamplerQuality::Fast);
+    }
+
+    #[test]
+    fn sinc_balanced() {
+        check(ResamplerEngine::Sinc, ResamplerQuality::Balanced);
+    }
+
+    #[test]
+    fn sinc_best() {
+        check(ResamplerEngine::Sinc, ResamplerQuality::Best);
+    }
+}
@@ -0,0 +1,265 @@
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Single-producer/single-consumer ring buffer of interleaved stereo samples. The ends never
+/// wait for each other, they only share the two counters, and a whole packet is handed over at
+/// once rather than one frame at a time.
+///
+/// Samples are always moved in whole frames (pairs of samples), so the consumer never sees a
+/// left sample without its right one.
+struct Shared {
+    buffer: Box<[UnsafeCell<f32>]>,
+    mask: usize,
+    /// Samples held at most, the buffer itself is rounded up to a power of two.
+    limit: usize,
+    /// Samples ever read, only advanced by the consumer.
+    read: AtomicUsize,
+    /// Samples ever written, only advanced by the producer.
+    written: AtomicUsize,
+}
+
+// The producer only writes to the free region and the consumer only reads from the filled one,
+// the atomics hand regions over between the two.
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn len(&self) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Acquire);
+        written.wrapping_sub(read)
+    }
+
+    fn base(&self) -> *mut f32 {
+        UnsafeCell::raw_get(self.buffer.as_ptr())
+    }
+}
+
+pub struct Producer(Arc<Shared>);
+
+pub struct Consumer(Arc<Shared>);
+
+/// Creates a ring buffer holding up to `limit` samples, rounded down to whole frames.
+pub fn channel(limit: usize) -> (Producer, Consumer) {
+    let limit = frames(limit).max(2);
+    let capacity = limit.next_power_of_two();
+
+    let shared = Arc::new(Shared {
+        buffer: (0..capacity).map(|_| UnsafeCell::new(0.0)).collect(),
+        mask: capacity - 1,
+        limit,
+        read: AtomicUsize::new(0),
+        written: AtomicUsize::new(0),
+    });
+
+    (Producer(shared.clone()), Consumer(shared))
+}
+
+/// Rounds down to whole stereo frames.
+fn frames(samples: usize) -> usize {
+    samples & !1
+}
+
+impl Producer {
+    /// Samples that can be pushed right now.
+    pub fn free(&self) -> usize {
+        let shared = &*self.0;
+        shared.limit - shared.len()
+    }
+
+    /// Copies as many whole frames of `samples` as fit and returns how many samples that was.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let shared = &*self.0;
+
+        let written = shared.written.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+
+        let free = shared.limit - written.wrapping_sub(read);
+        let count = frames(free.min(samples.len()));
+
+        let start = written & shared.mask;
+        let first = count.min(shared.capacity() - start);
+
+        unsafe {
+            ptr::copy_nonoverlapping(samples.as_ptr(), shared.base().add(start), first);
+            ptr::copy_nonoverlapping(samples.as_ptr().add(first), shared.base(), count - first);
+        }
+
+        shared
+            .written
+            .store(written.wrapping_add(count), Ordering::Release);
+
+        count
+    }
+}
+
+impl Consumer {
+    /// Samples currently buffered.
+    pub fn buffered(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Drops everything buffered.
+    pub fn clear(&mut self) {
+        let shared = &*self.0;
+
+        let written = shared.written.load(Ordering::Acquire);
+        shared.read.store(written, Ordering::Release);
+    }
+
+    /// Moves as many whole frames as are available into `out` and returns how many samples that
+    /// was.
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let shared = &*self.0;
+
+        let read = shared.read.load(Ordering::Relaxed);
+        let written = shared.written.load(Ordering::Acquire);
+
+        let available = written.wrapping_sub(read);
+        let count = frames(available.min(out.len()));
+
+        let start = read & shared.mask;
+        let first = count.min(shared.capacity() - start);
+
+        unsafe {
+            ptr::copy_nonoverlapping(shared.base().add(start), out.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(shared.base(), out.as_mut_ptr().add(first), count - first);
+        }
+
+        shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn limit_is_rounded_to_whole_frames() {
+        let (mut producer, _) = channel(7);
+        assert_eq!(producer.push(&[0.0; 10]), 6);
+
+        let (mut producer, _) = channel(0);
+        assert_eq!(producer.push(&[0.0; 4]), 2);
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let (mut producer, mut consumer) = channel(6);
+        let mut out = [0.0; 8];
+
+        assert_eq!(consumer.pop(&mut out), 0);
+
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]), 6);
+        assert_eq!(producer.push(&[9.0, 10.0]), 0);
+        assert_eq!(consumer.buffered(), 6);
+
+        assert_eq!(consumer.pop(&mut out), 6);
+        assert_eq!(out[..6], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(consumer.pop(&mut out), 0);
+        assert_eq!(producer.push(&[9.0, 10.0]), 2);
+    }
+
+    #[test]
+    fn only_whole_frames_move() {
+        let (mut producer, mut consumer) = channel(8);
+        let mut out = [0.0; 3];
+
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0]), 2);
+        assert_eq!(producer.push(&[3.0, 4.0]), 2);
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out[..2], [1.0, 2.0]);
+        assert_eq!(consumer.buffered(), 2);
+    }
+
+    #[test]
+    fn clear_drops_everything_buffered() {
+        let (mut producer, mut consumer) = channel(6);
+        let mut out = [0.0; 6];
+
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]), 6);
+        consumer.clear();
+        assert_eq!(consumer.buffered(), 0);
+        assert_eq!(consumer.pop(&mut out), 0);
+
+        assert_eq!(producer.push(&[7.0, 8.0, 9.0, 10.0, 11.0, 12.0]), 6);
+        assert_eq!(consumer.pop(&mut out), 6);
+        assert_eq!(out, [7.0, 8.0, 9.0, 10.0, 11.0, 12.0]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        // Holds 6 samples in a buffer of 8, so the ends keep crossing its end at new offsets
+        let (mut producer, mut consumer) = channel(6);
+        let mut next = 0.0;
+        let mut expected = 0.0;
+        let mut out = [0.0; 6];
+
+        for round in 0..100 {
+            let samples: Vec<f32> = (0..2 + round % 3 * 2)
+                .map(|_| {
+                    next += 1.0;
+                    next
+                })
+                .collect();
+            assert_eq!(producer.push(&samples), samples.len());
+
+            let popped = consumer.pop(&mut out);
+            assert_eq!(popped, samples.len());
+            for sample in &out[..popped] {
+                expected += 1.0;
+                assert_eq!(*sample, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_ends_keep_order() {
+        // Kept short under Miri, which runs the threads in an interpreter
+        let total = if cfg!(miri) { 2_000 } else { 2_000_000 };
+        let (mut producer, mut consumer) = channel(254);
+
+        let writer = thread::spawn(move || {
+            let mut next = 0usize;
+            while next < total {
+                // Vary the packet length so the ends cross the buffer's end at new offsets
+                let len = (2 + next % 37 * 2).min(total - next);
+                let samples: Vec<f32> = (next..next + len).map(|i| i as f32).collect();
+
+                let mut pushed = 0;
+                while pushed < len {
+                    pushed += producer.push(&samples[pushed..]);
+                    thread::yield_now();
+                }
+                next += len;
+            }
+        });
+
+        let mut expected = 0usize;
+        let mut out = [0.0; 96];
+        while expected < total {
+            let popped = consumer.pop(&mut out);
+            for sample in &out[..popped] {
+                assert_eq!(*sample, expected as f32);
+                expected += 1;
+            }
+            if popped == 0 {
+                thread::yield_now();
+            }
+        }
+
+        writer.join().unwrap();
+        assert_eq!(consumer.buffered(), 0);
+    }
+}
@@ -0,0 +1,196 @@
+use futures::StreamExt;
+use librespot::core::{
+    authentication::Credentials,
+    cache::Cache,
+    config::{DeviceType, SessionConfig},
+    session::{Session, SessionError},
+};
+use librespot::discovery::Discovery;
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+
+use super::config::{AuthMode, CachePaths, Profile};
+use super::error::{AoedeError, Result};
+use super::player::SpotifyPlayer;
+
+use std::sync::Arc;
+
+/// How often the supervisor checks whether a session is still alive.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// First delay between reconnection attempts, doubled after every failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Watches the session of a player and, once Spotify drops it, connects a new one and hands it
+/// to the player.
+pub fn supervise(player: Arc<Mutex<SpotifyPlayer>>) {
+    tokio::spawn(async move {
+        loop {
+            sleep(CHECK_INTERVAL).await;
+
+            let (profile, cache) = {
+                let player = player.lock().await;
+                if !player.is_session_invalid() {
+                    continue;
+                }
+                player.reconnect_params()
+            };
+
+            warn!(profile = %profile.name, "Spotify session was invalidated, reconnecting");
+
+            match connect_with_backoff(&profile, cache).await {
+                Ok(session) => {
+                    player.lock().await.replace_session(session).await;
+                    info!(profile = %profile.name, "Spotify session restored");
+                }
+                Err(why) => {
+                    // Retrying can't fix this, stop instead of failing every few seconds
+                    error!(profile = %profile.name, error = %why, "Giving up on Spotify session, restart Aoede once the problem is fixed");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Connects a session, retrying with exponential backoff while Spotify can't be reached. Other
+/// errors, like rejected credentials, are returned right away.
+pub async fn connect_with_backoff(profile: &Profile, cache: Option<Cache>) -> Result<Session> {
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        info!(profile = %profile.name, "Connecting Spotify session");
+
+        match connect(profile, cache.clone()).await {
+            Ok(session) => {
+                info!(profile = %profile.name, username = %session.username(), "Spotify session connected");
+                return Ok(session);
+            }
+            Err(AoedeError::Session(why)) => {
+                warn!(
+                    profile = %profile.name,
+                    error = %why,
+                    "Could not connect Spotify session, retrying in {:?}",
+                    backoff
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(why) => return Err(why),
+        }
+    }
+}
+
+/// Connects a session, preferring the reusable credentials librespot cached after the last
+/// successful login over the configured auth mode. Fresh credentials are written back to the
+/// cache, so neither the password nor discovery are needed on the next start.
+pub async fn connect(profile: &Profile, cache: Option<Cache>) -> Result<Session> {
+    let session_config = SessionConfig::default();
+
+    let cached = cache
+        .as_ref()
+        .and_then(Cache::credentials)
+        .filter(|credentials| {
+            profile
+                .spotify_username
+                .as_ref()
+                .is_none_or(|username| &credentials.username == username)
+        });
+
+    if let Some(cached) = cached {
+        match Session::connect(session_config.clone(), cached, cache.clone(), true).await {
+            Ok((session, _)) => return Ok(session),
+            // Cached credentials can expire, fall back to logging in again
+            Err(SessionError::AuthenticationError(why)) => {
+                warn!(profile = %profile.name, error = %why, "Cached credentials were rejected");
+            }
+            // Network errors are retried by the caller, don't fall back to discovery for them
+            Err(why) => return Err(why.into()),
+        }
+    }
+
+    let credentials = match profile.spotify_auth_mode {
+        AuthMode::Password => password_credentials(profile).ok_or_else(|| {
+            AoedeError::Auth(format!(
+                "no usable cached credentials for profile '{}', set its Spotify password or run `aoede login`",
+                profile.name
+            ))
+        })?,
+        AuthMode::Discovery => discover(profile, &session_config.device_id).await?,
+    };
+
+    let (session, _) = Session::connect(session_config, credentials, cache, true).await?;
+
+    Ok(session)
+}
+
+fn password_credentials(profile: &Profile) -> Option<Credentials> {
+    Some(Credentials::with_password(
+        profile.spotify_username.clone()?,
+        profile.spotify_password.clone()?,
+    ))
+}
+
+/// Advertises the Connect device on the local network and waits until it is selected in a
+/// Spotify app, which hands over the credentials of the account using it.
+async fn discover(profile: &Profile, device_id: &str) -> Result<Credentials> {
+    info!(
+        profile = %profile.name,
+        device = %profile.spotify_device_name,
+        "Waiting for the device to be selected in a Spotify app on the local network"
+    );
+
+    let mut discovery = Discovery::builder(device_id)
+        .name(profile.spotify_device_name.clone())
+        .device_type(DeviceType::AudioDongle)
+        .launch()
+        .map_err(|why| AoedeError::Auth(format!("could not start discovery: {}", why)))?;
+
+    let credentials = discovery.next().await.ok_or_else(|| {
+        AoedeError::Auth("discovery stopped before receiving credentials".to_string())
+    })?;
+
+    info!(
+        profile = %profile.name,
+        username = %credentials.username,
+        "Received credentials through discovery"
+    );
+
+    Ok(credentials)
+}
+
+/// Logs in once, with the password if given or through discovery otherwise, and stores the
+/// reusable credentials in the credentials cache. Returns the directory they were written to.
+pub async fn login(
+    profile: &Profile,
+    password: Option<String>,
+    cache_paths: CachePaths,
+) -> Result<String> {
+    let Some(credentials_dir) = cache_paths.credentials else {
+        return Err(AoedeError::Config(
+            "no credentials cache directory, set CACHE_DIR or CACHE_CREDENTIALS_DIR".to_string(),
+        ));
+    };
+
+    let cache = Cache::new(Some(&credentials_dir), None, None, None).map_err(|why| {
+        AoedeError::Config(format!("could not open {}: {}", credentials_dir, why))
+    })?;
+
+    let session_config = SessionConfig::default();
+
+    let credentials = match (password, &profile.spotify_username) {
+        (Some(password), Some(username)) => Credentials::with_password(username.clone(), password),
+        (Some(_), None) => {
+            return Err(AoedeError::Config(
+                "a spotify_username is needed to log in with a password".to_string(),
+            ))
+        }
+        (None, _) => discover(profile, &session_config.device_id).await?,
+    };
+
+    Session::connect(session_config, credentials, Some(cache), true).await?;
+
+    Ok(credentials_dir)
+}
@@ -0,0 +1,1028 @@
+// Lock order: the resampler, the crossfade, the player's events, then either one reader or the
+// subscriptions, and finally the recorder. Nothing is locked while a reader is held, except for
+// the counter of `Room`, which is always locked last and on its own otherwise. The writer never
+// holds the subscriptions while it waits for room, so subscribing and setting effects only wait
+// for the packet being pushed.
+
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::{
+    audio_backend,
+    audio_backend::{SinkError, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    player::{PlayerEvent, PlayerEventChannel},
+};
+
+use super::config::{AudioConfig, EffectConfig, LoudnessConfig, RecorderConfig};
+use super::crossfade::Crossfade;
+use super::dsp::Chain;
+use super::error::Result;
+use super::recorder::Recorder;
+use super::resample::StreamResampler;
+use super::ring;
+
+use std::clone::Clone;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+use std::{io, mem};
+
+use byteorder::{ByteOrder, LittleEndian};
+use songbird::constants::{FRAME_LEN_MS, SAMPLE_RATE_RAW, STEREO_FRAME_SIZE};
+use songbird::input::reader::MediaSource;
+use tracing::warn;
+
+/// How long to wait for a reader to make room before checking the buffers again. Readers wake
+/// the writer as soon as they take audio out, this only matters for readers that stopped.
+const WRITE_WAIT: Duration = Duration::from_millis(FRAME_LEN_MS as u64);
+
+/// Counters for tuning the target latency, shared by all clones of a sink.
+#[derive(Default)]
+pub struct SinkStats {
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+}
+
+impl SinkStats {
+    /// How often the buffer ran dry and silence was played instead. This includes pauses and
+    /// track loads.
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// How often the buffer stayed full for longer than the target latency, meaning songbird
+    /// stopped reading, or a reader of a broadcast fell behind the others.
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+}
+
+/// Reading end of the jitter buffer.
+struct Reader {
+    consumer: ring::Consumer,
+    /// Set while the buffer refills after running dry, silence is played until it holds half
+    /// the target latency again.
+    buffering: bool,
+    /// Scratch space for samples popped in `read`.
+    samples: Vec<f32>,
+    /// Faded out start of the audio dropped by the last flush, played before anything else.
+    fading_out: VecDeque<f32>,
+    /// Frames played since audio started after silence, counted up to the fade length.
+    faded_in: usize,
+}
+
+impl Reader {
+    /// Takes up to `fade_frames` from the start of the buffer and fades them out, so that
+    /// dropping the rest doesn't cut the waveform. Nothing to fade while silence is playing.
+    fn fade_out(&mut self, fade_frames: usize) {
+        if self.buffering || !self.fading_out.is_empty() {
+            return;
+        }
+
+        self.samples.resize(fade_frames * 2, 0.0);
+        let popped = self.consumer.pop(&mut self.samples);
+
+        fade_in(&mut self.faded_in, &mut self.samples[..popped], fade_frames);
+
+        let frames = popped / 2;
+        for (i, frame) in self.samples[..popped].chunks_exact(2).enumerate() {
+            let gain = 1.0 - (i + 1) as f32 / frames as f32;
+            self.fading_out
+                .extend(frame.iter().map(|sample| sample * gain));
+        }
+    }
+}
+
+/// Scales samples about to be played by the fade in that follows silence, `faded_in` frames
+/// into it.
+fn fade_in(faded_in: &mut usize, samples: &mut [f32], fade_frames: usize) {
+    for frame in samples.chunks_exact_mut(2) {
+        if *faded_in >= fade_frames {
+            return;
+        }
+
+        *faded_in += 1;
+        let gain = *faded_in as f32 / fade_frames as f32;
+        frame.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+/// Wakes the writer waiting for room whenever a reader takes audio out of its buffer.
+#[derive(Default)]
+struct Room {
+    /// How often audio was taken out, so that taking it out between the writer finding the
+    /// buffers full and starting to wait still wakes it.
+    taken: Mutex<u64>,
+    condvar: Condvar,
+}
+
+impl Room {
+    fn taken(&self) -> u64 {
+        *self.taken.lock().unwrap()
+    }
+
+    fn notify(&self) {
+        *self.taken.lock().unwrap() += 1;
+        self.condvar.notify_all();
+    }
+
+    /// Waits until audio is taken out after `taken` was read, or for at most `timeout`.
+    fn wait(&self, taken: u64, timeout: Duration) {
+        let guard = self.taken.lock().unwrap();
+        let _ = self
+            .condvar
+            .wait_timeout_while(guard, timeout, |now| *now == taken)
+            .unwrap();
+    }
+}
+
+/// Writing end of one reader's jitter buffer.
+struct Subscription {
+    producer: ring::Producer,
+    /// Gone once every clone reading from the buffer was dropped.
+    reader: Weak<Mutex<Reader>>,
+    /// Set while the reader fell behind and audio is dropped for it.
+    lagging: bool,
+    /// The effects of the guild the reader plays in, followed by the loudness limiter, run on
+    /// this reader's copy of the stream.
+    chain: Chain,
+    /// Processed samples waiting for room in the buffer.
+    processed: Vec<f32>,
+}
+
+pub struct EmittedSink {
+    // Each ring buffer has exactly one producer and one consumer, but librespot and songbird may
+    // briefly hold two clones of the sink while a player or track is replaced. The locks are
+    // uncontended otherwise and taken once per packet, not per frame.
+    /// Buffers of every reader, this clone's own included. Never held while waiting for room.
+    subscriptions: Arc<Mutex<Vec<Subscription>>>,
+    /// Buffer this clone reads from, shared with the clones it was cloned from.
+    reader: Arc<Mutex<Reader>>,
+    /// Wakes the writer, shared by every reader.
+    room: Arc<Room>,
+    stats: Arc<SinkStats>,
+    /// Samples buffered at most, the writer waits for songbird while the buffer is full.
+    target_samples: usize,
+    target_latency: Duration,
+    fade_frames: usize,
+    /// Loudness settings of the processors of every reader.
+    loudness: Option<LoudnessConfig>,
+    resampler: Arc<Mutex<StreamResampler>>,
+    /// Overlaps consecutive tracks. Always locked after the resampler.
+    crossfade: Arc<Mutex<Crossfade>>,
+    /// Events of the player writing to the sink, and what they told about the buffered audio.
+    /// librespot sends them from the thread that writes, so every event sent before a packet
+    /// has arrived by the time it is written. Always locked after the crossfade.
+    events: Arc<Mutex<Option<(PlayerEventChannel, StaleAudio)>>>,
+    /// Set from `stop` until `start`. Nothing is written in between, so the readers catch up
+    /// on the events instead, pausing sends its event only after stopping the sink.
+    stopped: Arc<AtomicBool>,
+    /// Tees what is pushed into this clone's buffer to files. Always locked last.
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl EmittedSink {
+    pub fn new(config: &AudioConfig) -> Result<EmittedSink> {
+        let target_samples =
+            (config.target_latency.as_secs_f64() * SAMPLE_RATE_RAW as f64) as usize * 2;
+
+        let resampler = StreamResampler::new(config.resampler, config.resampler_quality)?;
+
+        let subscriptions = Arc::new(Mutex::new(Vec::new()));
+        let reader = subscribe(&subscriptions, target_samples, config.loudness);
+
+        Ok(EmittedSink {
+            subscriptions,
+            reader,
+            room: Arc::new(Room::default()),
+            stats: Arc::new(SinkStats::default()),
+            target_samples,
+            target_latency: config.target_latency,
+            fade_frames: (config.fade.as_secs_f64() * SAMPLE_RATE_RAW as f64) as usize,
+            loudness: config.loudness,
+            resampler: Arc::new(Mutex::new(resampler)),
+            crossfade: Arc::new(Mutex::new(Crossfade::new(config.crossfade))),
+            events: Arc::new(Mutex::new(None)),
+            stopped: Arc::new(AtomicBool::new(true)),
+            recorder: Arc::new(Mutex::new(Recorder::default())),
+        })
+    }
+
+    /// Returns a sink reading the same stream from a buffer of its own, for playing it in
+    /// another place at once. Readers that fall behind lose audio instead of holding back the
+    /// others. Its effects are set separately and start out empty.
+    pub fn subscribe(&self) -> EmittedSink {
+        EmittedSink {
+            reader: subscribe(&self.subscriptions, self.target_samples, self.loudness),
+            ..self.clone()
+        }
+    }
+
+    pub fn stats(&self) -> &SinkStats {
+        &self.stats
+    }
+
+    /// How long dropping buffered audio takes to fade out.
+    pub fn fade(&self) -> Duration {
+        Duration::from_secs_f64(self.fade_frames as f64 / SAMPLE_RATE_RAW as f64)
+    }
+
+    /// Applies the effects of the guild this sink's reader plays in, the other readers keep
+    /// theirs.
+    pub fn set_effects(&self, effects: &[EffectConfig]) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if let Some(subscription) = subscriptions
+            .iter_mut()
+            .find(|subscription| self.reads(subscription))
+        {
+            subscription.chain.set_effects(effects);
+        }
+    }
+
+    /// Whether `subscription` is the buffer this clone reads from.
+    fn reads(&self, subscription: &Subscription) -> bool {
+        subscription.reader.as_ptr() == Arc::as_ptr(&self.reader)
+    }
+
+    /// Follows the events of the player writing to the sink, to find where tracks start and
+    /// end in the audio.
+    pub fn follow(&self, events: PlayerEventChannel) {
+        *self.events.lock().unwrap() = Some((events, StaleAudio::default()));
+    }
+
+    /// Whether tracks that play to their end overlap with the next one.
+    pub fn crossfades(&self) -> bool {
+        self.crossfade.lock().unwrap().is_enabled()
+    }
+
+    /// Plays `track_id` right after the current track, without overlap, if it comes next.
+    pub fn cut_to(&self, track_id: SpotifyId) {
+        self.crossfade.lock().unwrap().cut_to(track_id);
+    }
+
+    /// Starts recording the stream with `config`, or stops with `None`.
+    pub fn set_recording(&self, config: Option<RecorderConfig>) {
+        self.recorder.lock().unwrap().set(config);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.lock().unwrap().is_recording()
+    }
+
+    /// Names the recording of `track_id` after its artist and title.
+    pub fn name_recording(&self, track_id: SpotifyId, artist: Option<&str>, title: &str) {
+        self.recorder.lock().unwrap().name(track_id, artist, title);
+    }
+
+    /// Handles the player's events sent before the packet about to be written, or before the
+    /// sink was read while stopped. Drops the buffered audio as soon as an event makes it stale.
+    fn catch_up(&self, resampler: &mut StreamResampler, crossfade: &mut Crossfade) -> Result<()> {
+        let mut events = self.events.lock().unwrap();
+        let Some((events, stale_audio)) = events.as_mut() else {
+            return Ok(());
+        };
+
+        while let Ok(event) = events.try_recv() {
+            if stale_audio.after(&event) {
+                self.flush(resampler, crossfade)?;
+            }
+
+            let in_flight = resampler.pending() + crossfade.held() + self.delay();
+            crossfade.event(&event, resampler.pending());
+            self.recorder.lock().unwrap().event(&event, in_flight);
+        }
+
+        Ok(())
+    }
+
+    /// Catches up on the player's events from the thread reading the sink, while nothing is
+    /// written. Leaves them to `write` if the sink was just started again.
+    fn catch_up_stopped(&self) {
+        let Ok(mut resampler) = self.resampler.try_lock() else {
+            return;
+        };
+        let mut crossfade = self.crossfade.lock().unwrap();
+
+        if let Err(why) = self.catch_up(&mut resampler, &mut crossfade) {
+            warn!(error = %why, "Could not drop stale audio");
+        }
+    }
+
+    /// Drops all buffered audio and the resampler and processor state, so that whatever
+    /// librespot writes next plays right away. What is playing fades out first.
+    fn flush(&self, resampler: &mut StreamResampler, crossfade: &mut Crossfade) -> Result<()> {
+        for reader in self.readers() {
+            let mut reader = reader.lock().unwrap();
+            reader.fade_out(self.fade_frames);
+            reader.consumer.clear();
+            reader.buffering = true;
+        }
+
+        resampler.reset()?;
+        crossfade.reset();
+
+        for subscription in self.subscriptions.lock().unwrap().iter_mut() {
+            subscription.chain.reset();
+            subscription.processed.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Frames the processors hold back. Only the effects differ between readers, which don't
+    /// delay the stream.
+    fn delay(&self) -> usize {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions
+            .first()
+            .map_or(0, |subscription| subscription.chain.delay())
+    }
+
+    fn readers(&self) -> Vec<Arc<Mutex<Reader>>> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions
+            .iter()
+            .filter_map(|subscription| subscription.reader.upgrade())
+            .collect()
+    }
+
+    /// Runs interleaved samples through the processors of every reader and pushes them into
+    /// the buffers.
+    fn push(&self, samples: &[f32]) {
+        self.process(|chain, processed| processed.extend_from_slice(chain.process(samples)));
+        self.deliver();
+    }
+
+    /// Pushes what the processors of every reader still hold into the buffers.
+    fn push_drained(&self) {
+        self.process(|chain, processed| processed.extend_from_slice(chain.drain()));
+        self.deliver();
+    }
+
+    /// Adds the output of `run` on each reader's processors to what waits for room in its
+    /// buffer. What this clone's reader gets is recorded.
+    fn process(&self, mut run: impl FnMut(&mut Chain, &mut Vec<f32>)) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+
+        for subscription in subscriptions.iter_mut() {
+            let start = subscription.processed.len();
+            run(&mut subscription.chain, &mut subscription.processed);
+
+            if self.reads(subscription) {
+                self.recorder
+                    .lock()
+                    .unwrap()
+                    .write(&subscription.processed[start..]);
+            }
+        }
+    }
+
+    /// Pushes the processed samples into the buffers, waiting for the fastest reader to make
+    /// room.
+    fn deliver(&self) {
+        let mut full_since = None;
+        let mut stalled = false;
+
+        loop {
+            let taken = self.room.taken();
+            let (pushed, left) = self.fan_out();
+
+            if left == 0 {
+                return;
+            }
+
+            if pushed > 0 {
+                full_since = None;
+                stalled = false;
+                continue;
+            }
+
+            // Count a stalled reader once, not once per wait
+            let full_since = *full_since.get_or_insert_with(Instant::now);
+            if !stalled && full_since.elapsed() > self.target_latency {
+                stalled = true;
+                self.stats.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+
+            self.room.wait(taken, WRITE_WAIT);
+        }
+    }
+
+    /// Pushes as many processed samples as the emptiest buffer has room for into every buffer,
+    /// and returns how many that was and how many are left. The other buffers drop what doesn't
+    /// fit.
+    fn fan_out(&self) -> (usize, usize) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|subscription| subscription.reader.strong_count() > 0);
+
+        // Readers subscribed while the others wait have less
+        let left = subscriptions
+            .iter()
+            .map(|subscription| subscription.processed.len())
+            .max()
+            .unwrap_or(0);
+        let count = subscriptions
+            .iter()
+            .map(|subscription| subscription.producer.free())
+            .max()
+            .unwrap_or(0)
+            .min(left);
+
+        if count == 0 {
+            return (0, left);
+        }
+
+        for subscription in subscriptions.iter_mut() {
+            let count = count.min(subscription.processed.len());
+            let lagging = subscription.producer.push(&subscription.processed[..count]) < count;
+            subscription.processed.drain(..count);
+
+            // Count a reader falling behind once, not once per packet
+            if lagging && !subscription.lagging {
+                self.stats.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+            subscription.lagging = lagging;
+        }
+
+        (count, left - count)
+    }
+}
+
+/// Adds a buffer holding up to `target_samples` to `subscriptions` and returns its reading end.
+fn subscribe(
+    subscriptions: &Mutex<Vec<Subscription>>,
+    target_samples: usize,
+    loudness: Option<LoudnessConfig>,
+) -> Arc<Mutex<Reader>> {
+    let (producer, consumer) = ring::channel(target_samples);
+
+    let reader = Arc::new(Mutex::new(Reader {
+        consumer,
+        buffering: true,
+        samples: Vec::new(),
+        fading_out: VecDeque::new(),
+        faded_in: 0,
+    }));
+
+    subscriptions.lock().unwrap().push(Subscription {
+        producer,
+        reader: Arc::downgrade(&reader),
+        lagging: false,
+        chain: Chain::new(loudness),
+        processed: Vec::new(),
+    });
+
+    reader
+}
+
+/// Tells from the player's events when buffered audio went stale: on pause and stop, when a
+/// track is skipped rather than played to its end, and when the position jumps.
+#[derive(Default)]
+struct StaleAudio {
+    /// Track and position last reported, and when playback was last known to be there. `None`
+    /// for the instant while paused.
+    position: Option<(SpotifyId, u32, Option<Instant>)>,
+    end_of_track: bool,
+}
+
+/// Position reports further than this from where playback should be are taken as a seek.
+const SEEK_TOLERANCE_MS: i64 = 1000;
+
+impl StaleAudio {
+    /// Whether the sink should be flushed after `event`.
+    fn after(&mut self, event: &PlayerEvent) -> bool {
+        match *event {
+            PlayerEvent::Stopped { .. } => {
+                self.position = None;
+                true
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.position = Some((track_id, position_ms, None));
+                true
+            }
+            PlayerEvent::EndOfTrack { .. } => {
+                self.end_of_track = true;
+                false
+            }
+            // Also sent when a track ends on its own, its tail is still playing then
+            PlayerEvent::Changed { .. } => !mem::take(&mut self.end_of_track),
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                let expected = match self.position {
+                    Some((id, position, since)) if id == track_id => {
+                        let elapsed = since.map_or(0, |since| since.elapsed().as_millis() as i64);
+                        Some(position as i64 + elapsed)
+                    }
+                    _ => None,
+                };
+
+                self.position = Some((track_id, position_ms, Some(Instant::now())));
+
+                expected.is_some_and(|expected| {
+                    (position_ms as i64 - expected).abs() > SEEK_TOLERANCE_MS
+                })
+            }
+            _ => false,
+        }
+    }
+}
+
+impl audio_backend::Sink for EmittedSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.stopped.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Called when playback stops, pauses or a track is loaded without gapless playback. Gives
+    /// out the audio the resampler and crossfade still hold, which would otherwise be lost or
+    /// prepended to whatever plays next.
+    ///
+    /// librespot exits the process on any error from the sink, so only a resampler that can't
+    /// be rebuilt is reported.
+    fn stop(&mut self) -> SinkResult<()> {
+        let mut resampler = self.resampler.lock().unwrap();
+        let mut crossfade = self.crossfade.lock().unwrap();
+
+        self.catch_up(&mut resampler, &mut crossfade)
+            .map_err(|why| SinkError::OnWrite(why.to_string()))?;
+
+        if let Err(why) = resampler.finish(|samples| self.push(crossfade.process(samples))) {
+            warn!(error = %why, "Could not resample the end of the stream, dropping it");
+            resampler
+                .reset()
+                .map_err(|why| SinkError::OnWrite(why.to_string()))?;
+        }
+
+        // Whatever comes next doesn't continue this track, don't overlap with it
+        self.push(crossfade.drain());
+        self.push_drained();
+
+        self.recorder.lock().unwrap().sync();
+        self.stopped.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
+        let mut resampler = self.resampler.lock().unwrap();
+        let mut crossfade = self.crossfade.lock().unwrap();
+
+        self.catch_up(&mut resampler, &mut crossfade)
+            .map_err(|why| SinkError::OnWrite(why.to_string()))?;
+
+        let samples = packet
+            .samples()
+            .map_err(|why| SinkError::OnWrite(why.to_string()))?;
+
+        for c in samples.chunks_exact(2) {
+            let resampled = match resampler.push(c[0] as f32, c[1] as f32) {
+                Ok(resampled) => resampled,
+                Err(why) => {
+                    // Skip the rest of the packet rather than have librespot exit
+                    warn!(error = %why, "Could not resample, dropping the packet");
+                    return resampler
+                        .reset()
+                        .map_err(|why| SinkError::OnWrite(why.to_string()));
+                }
+            };
+
+            if let Some(resampled) = resampled {
+                self.push(crossfade.process(resampled));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl io::Read for EmittedSink {
+    fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
+        let sample_size = mem::size_of::<f32>() * 2;
+
+        if buff.len() < sample_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "EmittedSink does not support read buffer too small to guarantee \
+                holding one audio sample (8 bytes)",
+            ));
+        }
+
+        if self.stopped.load(Ordering::Acquire) {
+            self.catch_up_stopped();
+        }
+
+        let mut reader = self.reader.lock().unwrap();
+        let reader = &mut *reader;
+
+        let samples = buff.len() / mem::size_of::<f32>();
+
+        if !reader.fading_out.is_empty() {
+            let faded = reader.fading_out.len().min(samples & !1);
+            for (sample, bytes) in reader
+                .fading_out
+                .drain(..faded)
+                .zip(buff.chunks_exact_mut(mem::size_of::<f32>()))
+            {
+                LittleEndian::write_f32(bytes, sample);
+            }
+
+            return Ok(faded * mem::size_of::<f32>());
+        }
+
+        reader.samples.resize(samples, 0.0);
+
+        if reader.buffering && reader.consumer.buffered() >= self.target_samples / 2 {
+            reader.buffering = false;
+            reader.faded_in = 0;
+        }
+
+        let popped = if reader.buffering {
+            0
+        } else {
+            reader.consumer.pop(&mut reader.samples)
+        };
+
+        if popped > 0 {
+            self.room.notify();
+
+            fade_in(
+                &mut reader.faded_in,
+                &mut reader.samples[..popped],
+                self.fade_frames,
+            );
+
+            let bytes_written = popped * mem::size_of::<f32>();
+            LittleEndian::write_f32_into(&reader.samples[..popped], &mut buff[..bytes_written]);
+
+            return Ok(bytes_written);
+        }
+
+        if !reader.buffering {
+            reader.buffering = true;
+            self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // We can not return 0 bytes because songbird then thinks that the track has ended, and
+        // blocking would stall its mixer. Play at most one frame of silence so the buffer isn't
+        // pushed back further than needed once audio arrives again.
+        let silence = samples.min(STEREO_FRAME_SIZE) & !1;
+        let bytes_written = silence * mem::size_of::<f32>();
+        buff[..bytes_written].fill(0);
+
+        Ok(bytes_written)
+    }
+}
+
+impl io::Seek for EmittedSink {
+    fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> {
+        unreachable!()
+    }
+}
+
+impl MediaSource for EmittedSink {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl Clone for EmittedSink {
+    fn clone(&self) -> EmittedSink {
+        EmittedSink {
+            subscriptions: self.subscriptions.clone(),
+            reader: self.reader.clone(),
+            room: self.room.clone(),
+            stats: self.stats.clone(),
+            target_samples: self.target_samples,
+            target_latency: self.target_latency,
+            fade_frames: self.fade_frames,
+            loudness: self.loudness,
+            resampler: self.resampler.clone(),
+            crossfade: self.crossfade.clone(),
+            events: self.events.clone(),
+            stopped: self.stopped.clone(),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lib::config::{LoudnessConfig, RecorderFormat, ResamplerEngine, ResamplerQuality};
+    use audio_backend::Sink;
+    use std::fs;
+    use std::thread;
+    use tokio::sync::mpsc;
+
+    fn config() -> AudioConfig {
+        AudioConfig {
+            target_latency: Duration::from_secs(1),
+            fade: Duration::ZERO,
+            resampler: ResamplerEngine::Fft,
+            resampler_quality: ResamplerQuality::Balanced,
+            loudness: None,
+            crossfade: Duration::ZERO,
+        }
+    }
+
+    fn sink() -> EmittedSink {
+        EmittedSink::new(&config()).unwrap()
+    }
+
+    fn track(id: &str) -> SpotifyId {
+        SpotifyId::from_base62(id).unwrap()
+    }
+
+    fn playing(position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Playing {
+            track_id: track("4uLU6hMCjMI75M1A2tKUQC"),
+            play_request_id: 0,
+            position_ms,
+            duration_ms: 300_000,
+        }
+    }
+
+    /// Writes a tenth of a second of `value` at librespot's 44.1 kHz.
+    fn write(sink: &mut EmittedSink, value: f64) {
+        let packet = AudioPacket::Samples(vec![value; 4410 * 2]);
+        sink.write(packet, &mut Converter::new(None)).unwrap();
+    }
+
+    fn buffered(sink: &EmittedSink) -> Vec<f32> {
+        let mut reader = sink.reader.lock().unwrap();
+        let mut samples = vec![0.0; reader.consumer.buffered()];
+        reader.consumer.pop(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn seek_drops_only_the_audio_before_it() {
+        let mut sink = sink();
+        let (events, channel) = mpsc::unbounded_channel();
+        sink.follow(channel);
+
+        sink.start().unwrap();
+        events.send(playing(0)).unwrap();
+        write(&mut sink, 0.25);
+        write(&mut sink, 0.25);
+
+        events.send(playing(60_000)).unwrap();
+        write(&mut sink, -0.5);
+        write(&mut sink, -0.5);
+
+        let samples = buffered(&sink);
+        assert!(!samples.is_empty());
+        // Nothing near the level written before the seek, the fresh resampler only rings a little
+        assert!(samples.iter().all(|sample| *sample < 0.1));
+    }
+
+    #[test]
+    fn pause_drops_the_audio_when_read_while_stopped() {
+        let mut sink = sink();
+        let (events, channel) = mpsc::unbounded_channel();
+        sink.follow(channel);
+
+        sink.start().unwrap();
+        events.send(playing(0)).unwrap();
+        write(&mut sink, 0.25);
+        write(&mut sink, 0.25);
+
+        // librespot stops the sink before it sends the event
+        sink.stop().unwrap();
+        events
+            .send(PlayerEvent::Paused {
+                track_id: track("4uLU6hMCjMI75M1A2tKUQC"),
+                play_request_id: 0,
+                position_ms: 200,
+                duration_ms: 300_000,
+            })
+            .unwrap();
+        assert!(sink.reader.lock().unwrap().consumer.buffered() > 0);
+
+        io::Read::read(&mut sink, &mut [0; STEREO_FRAME_SIZE * 4]).unwrap();
+        assert_eq!(sink.reader.lock().unwrap().consumer.buffered(), 0);
+    }
+
+    #[test]
+    fn broadcast_readers_get_the_whole_stream_with_their_own_effects() {
+        let mut sink = sink();
+        let broadcast = sink.subscribe();
+        // Boosts a constant signal by 12 dB, four times
+        broadcast.set_effects(&[EffectConfig::Bass {
+            frequency: 100.0,
+            gain_db: 12.0,
+        }]);
+
+        sink.start().unwrap();
+        for _ in 0..3 {
+            write(&mut sink, 0.1);
+        }
+
+        let (own, broadcast) = (buffered(&sink), buffered(&broadcast));
+        assert_eq!(own.len(), broadcast.len());
+        assert!((own[own.len() - 1] - 0.1).abs() < 0.001);
+        assert!((broadcast[broadcast.len() - 1] - 0.398).abs() < 0.001);
+    }
+
+    #[test]
+    fn a_reader_that_stops_does_not_hold_back_the_others() {
+        let mut sink = sink();
+        let stopped = sink.subscribe();
+
+        // Twice the target latency, the own reader keeping up
+        sink.start().unwrap();
+        for _ in 0..20 {
+            write(&mut sink, 0.25);
+            buffered(&sink);
+        }
+
+        assert_eq!(
+            stopped.reader.lock().unwrap().consumer.buffered(),
+            sink.target_samples
+        );
+        assert_eq!(sink.stats().overruns(), 1);
+    }
+
+    #[test]
+    fn guilds_subscribe_and_leave_while_effects_are_swapped() {
+        let (finished, watchdog) = std::sync::mpsc::channel();
+
+        thread::spawn(move || {
+            let mut sink = sink();
+            let done = Arc::new(AtomicBool::new(false));
+
+            // songbird reading the stream of the followed user's guild
+            let reader = {
+                let mut own = sink.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        io::Read::read(&mut own, &mut [0; STEREO_FRAME_SIZE * 4]).unwrap();
+                    }
+                })
+            };
+
+            // Broadcast guilds joining and leaving, each with effects of its own
+            let guilds: Vec<_> = (0..4)
+                .map(|guild| {
+                    let sink = sink.clone();
+                    thread::spawn(move || {
+                        for _ in 0..50 {
+                            let mut broadcast = sink.subscribe();
+                            broadcast.set_effects(&[EffectConfig::Bass {
+                                frequency: 100.0,
+                                gain_db: guild as f64,
+                            }]);
+                            io::Read::read(&mut broadcast, &mut [0; STEREO_FRAME_SIZE * 4])
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect();
+
+            // Reloads swapping the effects of the followed user's guild
+            let reload = {
+                let sink = sink.clone();
+                thread::spawn(move || {
+                    for round in 0..200 {
+                        let effects = if round % 2 == 0 {
+                            vec![EffectConfig::Eq {
+                                frequency: 1000.0,
+                                gain_db: 3.0,
+                                q: 1.0,
+                            }]
+                        } else {
+                            Vec::new()
+                        };
+                        sink.set_effects(&effects);
+                    }
+                })
+            };
+
+            sink.start().unwrap();
+            for _ in 0..20 {
+                write(&mut sink, 0.25);
+            }
+
+            for guild in guilds {
+                guild.join().unwrap();
+            }
+            reload.join().unwrap();
+
+            // The buffers of guilds that left are dropped with the next packet
+            write(&mut sink, 0.25);
+            assert_eq!(sink.subscriptions.lock().unwrap().len(), 1);
+
+            done.store(true, Ordering::Relaxed);
+            reader.join().unwrap();
+            finished.send(()).unwrap();
+        });
+
+        watchdog
+            .recv_timeout(Duration::from_secs(60))
+            .expect("the sink deadlocked or a thread panicked");
+    }
+
+    /// Samples of the WAV recording of `track_id` in `dir`.
+    fn recorded(dir: &std::path::Path, track_id: &str) -> Vec<f32> {
+        let path = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.to_string_lossy()
+                    .ends_with(&format!("{}.wav", track_id))
+            })
+            .unwrap();
+
+        // Past the header of a float WAV file
+        fs::read(path).unwrap()[58..]
+            .chunks_exact(4)
+            .map(LittleEndian::read_f32)
+            .collect()
+    }
+
+    #[test]
+    fn recordings_split_where_the_audio_of_a_track_ends() {
+        let (first, second) = ("4uLU6hMCjMI75M1A2tKUQC", "6rqhFgbbKwnb9MLmUQDhG6");
+
+        // Everything that holds audio back, so that the player's events come early
+        let mut sink = EmittedSink::new(&AudioConfig {
+            loudness: Some(LoudnessConfig {
+                target_lufs: -16.0,
+                ceiling_dbtp: -1.0,
+            }),
+            crossfade: Duration::from_millis(100),
+            ..config()
+        })
+        .unwrap();
+        let (events, channel) = mpsc::unbounded_channel();
+        sink.follow(channel);
+
+        let dir = std::env::temp_dir().join(format!("aoede-{}-recordings", std::process::id()));
+        sink.set_recording(Some(RecorderConfig {
+            dir: dir.clone(),
+            format: RecorderFormat::Wav,
+        }));
+
+        sink.start().unwrap();
+        events
+            .send(PlayerEvent::Started {
+                play_request_id: 0,
+                track_id: track(first),
+                position_ms: 0,
+            })
+            .unwrap();
+        events
+            .send(PlayerEvent::Playing {
+                track_id: track(first),
+                play_request_id: 0,
+                position_ms: 0,
+                duration_ms: 300,
+            })
+            .unwrap();
+        for _ in 0..3 {
+            write(&mut sink, 0.25);
+        }
+
+        events
+            .send(PlayerEvent::EndOfTrack {
+                play_request_id: 0,
+                track_id: track(first),
+            })
+            .unwrap();
+        events
+            .send(PlayerEvent::Changed {
+                old_track_id: track(first),
+                new_track_id: track(second),
+            })
+            .unwrap();
+        events.send(playing(0)).unwrap();
+        for _ in 0..2 {
+            write(&mut sink, -0.25);
+        }
+        sink.stop().unwrap();
+
+        sink.recorder.lock().unwrap().join();
+        let (first, second) = (recorded(&dir, first), recorded(&dir, second));
+        fs::remove_dir_all(&dir).unwrap();
+
+        // All 300 ms of the first track, the overlap with the next one included
+        assert_eq!(first.len(), 14_400 * 2);
+        assert_eq!(second.len(), (9_600 - 4_800) * 2);
+        assert!(first.iter().sum::<f32>() > 0.0);
+        assert!(second.iter().all(|sample| *sample < 0.0));
+    }
+}
@@ -0,0 +1,58 @@
+use songbird::constants::SAMPLE_RATE_RAW;
+
+/// Sample format of PCM written as is.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PcmFormat {
+    F32,
+    S16,
+}
+
+impl PcmFormat {
+    pub fn bytes_per_sample(self) -> u32 {
+        match self {
+            PcmFormat::F32 => 4,
+            PcmFormat::S16 => 2,
+        }
+    }
+}
+
+/// RIFF header of a stereo WAV file with `frames` of `format` samples. Lengths that don't fit
+/// are capped, so `u64::MAX` makes a header for a stream of unknown length.
+pub fn wav_header(format: PcmFormat, frames: u64) -> Vec<u8> {
+    let frame_size = format.bytes_per_sample() * 2;
+    // Anything but integer samples needs the extended fmt chunk and a fact chunk
+    let float = format == PcmFormat::F32;
+    let header_size = if float { 58 } else { 44 };
+
+    let data_size = frames
+        .saturating_mul(frame_size as u64)
+        .min(u32::MAX as u64 - (header_size - 8) as u64) as u32;
+
+    let mut header = Vec::with_capacity(header_size);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&((header_size - 8) as u32 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&(if float { 18u32 } else { 16 }).to_le_bytes());
+    // Format, stereo, then bytes per second, per frame and bits per sample
+    header.extend_from_slice(&(if float { 3u16 } else { 1 }).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE_RAW as u32).to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE_RAW as u32 * frame_size).to_le_bytes());
+    header.extend_from_slice(&(frame_size as u16).to_le_bytes());
+    header.extend_from_slice(&(format.bytes_per_sample() as u16 * 8).to_le_bytes());
+
+    if float {
+        header.extend_from_slice(&0u16.to_le_bytes());
+
+        header.extend_from_slice(b"fact");
+        header.extend_from_slice(&4u32.to_le_bytes());
+        header.extend_from_slice(&(data_size / frame_size).to_le_bytes());
+    }
+
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+
+    header
+}
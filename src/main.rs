@@ -1,314 +1,813 @@
 use std::env;
+use std::io;
 use std::process::exit;
 
-use lib::config::Config;
-use songbird::{input, SerenityInit};
+use lib::config::{AuthMode, Config, ConfigKey, GuildConfig, Profile};
+use songbird::{constants::FRAME_LEN_MS, input, tracks::TrackHandle, SerenityInit, Songbird};
 
 mod lib {
     pub mod config;
+    pub mod crossfade;
+    pub mod dsp;
+    pub mod error;
+    pub mod explain;
+    pub mod loudness;
+    pub mod mixer;
+    pub mod pipe;
     pub mod player;
+    pub mod recorder;
+    pub mod reload;
+    pub mod resample;
+    pub mod ring;
+    pub mod session;
+    pub mod sink;
+    pub mod wav;
 }
 use figment::error::Kind::MissingField;
+use lib::error::{AoedeError, Result};
+use lib::pipe::{self, PipeOptions};
 use lib::player::{SpotifyPlayer, SpotifyPlayerKey};
-use librespot::core::mercury::MercuryError;
-use librespot::playback::config::Bitrate;
+use lib::sink::EmittedSink;
+use lib::{crossfade, explain, recorder, reload, session};
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::{Album, Artist, Metadata, Track};
 use librespot::playback::player::PlayerEvent;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 use serenity::Client;
 
-use serenity::prelude::TypeMapKey;
-
 use serenity::{
     async_trait,
     client::{Context, EventHandler},
     framework::StandardFramework,
     model::{gateway, gateway::Ready, id, user, voice::VoiceState},
+    prelude::TypeMapKey,
 };
 
 struct Handler;
 
-pub struct ConfigKey;
-impl TypeMapKey for ConfigKey {
-    type Value = Config;
+/// Players whose session is up, waiting to be handed to the bot once its cache is ready.
+struct StartedPlayersKey;
+
+impl TypeMapKey for StartedPlayersKey {
+    type Value = Option<mpsc::UnboundedReceiver<Arc<Mutex<SpotifyPlayer>>>>;
+}
+
+/// Profiles whose followed users changed on reload, waiting to be looked for in voice again.
+struct RefollowKey;
+
+impl TypeMapKey for RefollowKey {
+    type Value = Option<mpsc::UnboundedReceiver<String>>;
 }
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, _ctx: Context, ready: Ready) {
-        println!("Ready!");
-        println!("Invite me with https://discord.com/api/oauth2/authorize?client_id={}&permissions=36700160&scope=bot", ready.user.id);
+        info!("Ready!");
+        info!("Invite me with https://discord.com/api/oauth2/authorize?client_id={}&permissions=36702208&scope=bot", ready.user.id);
     }
 
-    async fn cache_ready(&self, ctx: Context, guilds: Vec<id::GuildId>) {
-        let data = ctx.data.read().await;
-
-        let player = data.get::<SpotifyPlayerKey>().unwrap().clone();
-        let config = data.get::<ConfigKey>().unwrap().clone();
-
-        // Handle case when user is in VC when bot starts
-        for guild_id in guilds {
-            let guild = ctx
-                .cache
-                .guild(guild_id)
-                .expect("Could not find guild in cache.");
-
-            let channel_id = guild
-                .voice_states
-                .get(&config.discord_user_id.into())
-                .and_then(|voice_state| voice_state.channel_id);
-            drop(guild);
-
-            if channel_id.is_some() {
-                // Enable casting
-                player.lock().await.enable_connect().await;
-                break;
-            }
+    async fn cache_ready(&self, ctx: Context, _guilds: Vec<id::GuildId>) {
+        let (started, refollow) = {
+            let mut data = ctx.data.write().await;
+            (
+                data.get_mut::<StartedPlayersKey>().and_then(Option::take),
+                data.get_mut::<RefollowKey>().and_then(Option::take),
+            )
+        };
+
+        if let Some(mut refollow) = refollow {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                while let Some(name) = refollow.recv().await {
+                    refollow_profile(&ctx, &name).await;
+                }
+            });
         }
 
-        let c = ctx.clone();
-
-        // Handle Spotify events
-        tokio::spawn(async move {
-            loop {
-                let channel = player.lock().await.event_channel.clone().unwrap();
-                let mut receiver = channel.lock().await;
-
-                let event = match receiver.recv().await {
-                    Some(e) => e,
-                    None => {
-                        // Busy waiting bad but quick and easy
-                        sleep(Duration::from_millis(256)).await;
-                        continue;
-                    }
-                };
-
-                match event {
-                    PlayerEvent::Stopped { .. } => {
-                        c.set_presence(None, user::OnlineStatus::Online).await;
-
-                        let manager = songbird::get(&c)
-                            .await
-                            .expect("Songbird Voice client placed in at initialization.")
-                            .clone();
-
-                        for guild_id in c.cache.guilds() {
-                            let _ = manager.remove(guild_id).await;
-                        }
-                    }
-
-                    PlayerEvent::Started { .. } => {
-                        let manager = songbird::get(&c)
-                            .await
-                            .expect("Songbird Voice client placed in at initialization.")
-                            .clone();
-
-                        // Search for guild and channel ids by user id
-                        let Some((guild_id, channel_id)) =
-                            c.cache.guilds().iter().find_map(|gid| {
-                                c.cache
-                                    .guild(gid)
-                                    .expect("Could not find guild in cache.")
-                                    .voice_states
-                                    .get(&config.discord_user_id.into())
-                                    .map(|state| (gid.to_owned(), state.channel_id.unwrap()))
-                            })
-                        else {
-                            println!("Could not find user in VC.");
-                            continue;
-                        };
-
-                        let _handler = manager.join(guild_id, channel_id).await;
-
-                        if let Some(handler_lock) = manager.get(guild_id) {
-                            let mut handler = handler_lock.lock().await;
-
-                            let mut decoder = input::codec::OpusDecoderState::new().unwrap();
-                            decoder.allow_passthrough = false;
-
-                            let source = input::Input::new(
-                                true,
-                                input::reader::Reader::Extension(Box::new(
-                                    player.lock().await.emitted_sink.clone(),
-                                )),
-                                input::codec::Codec::FloatPcm,
-                                input::Container::Raw,
-                                None,
-                            );
-
-                            handler.set_bitrate(songbird::driver::Bitrate::Auto);
-
-                            handler.play_only_source(source);
-                        } else {
-                            println!("Could not fetch guild by ID.");
-                        }
-                    }
-
-                    PlayerEvent::Paused { .. } => {
-                        c.set_presence(None, user::OnlineStatus::Online).await;
-                    }
-
-                    PlayerEvent::Playing { track_id, .. } => {
-                        let track: Result<librespot::metadata::Track, MercuryError> =
-                            librespot::metadata::Metadata::get(
-                                &player.lock().await.session,
-                                track_id,
-                            )
-                            .await;
-
-                        if let Ok(track) = track {
-                            let artist: Result<librespot::metadata::Artist, MercuryError> =
-                                librespot::metadata::Metadata::get(
-                                    &player.lock().await.session,
-                                    *track.artists.first().unwrap(),
-                                )
-                                .await;
-
-                            if let Ok(artist) = artist {
-                                let listening_to = format!("{}: {}", artist.name, track.name);
-
-                                c.set_presence(
-                                    Some(gateway::Activity::listening(listening_to)),
-                                    user::OnlineStatus::Online,
-                                )
-                                .await;
-                            }
-                        }
-                    }
-
-                    _ => {}
+        if let Some(mut started) = started {
+            tokio::spawn(async move {
+                while let Some(player) = started.recv().await {
+                    adopt_player(&ctx, player).await;
                 }
-            }
-        });
+            });
+        }
     }
 
-    async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
+    async fn voice_state_update(&self, ctx: Context, _old: Option<VoiceState>, new: VoiceState) {
         let data = ctx.data.read().await;
 
         let config = data.get::<ConfigKey>().unwrap();
+        let players = data.get::<SpotifyPlayerKey>().unwrap();
+
+        let manager = match voice_manager(&ctx).await {
+            Ok(manager) => manager,
+            Err(why) => {
+                error!(error = %why, "Voice is unavailable");
+                return;
+            }
+        };
+
+        for profile in config.profiles() {
+            if !profile.discord_user_ids.contains(&new.user_id.0) {
+                continue;
+            }
+
+            if let Some(player) = players.get(&profile.name) {
+                if let Err(why) =
+                    follow_profile(&ctx, &manager, config, &profile, player, players).await
+                {
+                    warn!(profile = %profile.name, error = %why, "Could not follow profile");
+                }
+            }
+        }
+
+        if !any_connect_enabled(players).await {
+            ctx.invisible().await;
+        }
+    }
+}
 
-        if new.user_id.to_string() != config.discord_user_id.to_string() {
+type Players = HashMap<String, Arc<Mutex<SpotifyPlayer>>>;
+
+/// Connects the player of a profile, retrying while Spotify can't be reached, and hands it to
+/// the bot once its session is up.
+async fn start_profile(
+    config: Config,
+    profile: Profile,
+    started: mpsc::UnboundedSender<Arc<Mutex<SpotifyPlayer>>>,
+) {
+    let player = match SpotifyPlayer::new(
+        &profile,
+        config.player_config(),
+        config.mixer_config(),
+        config.audio_config(),
+        config.cache_paths(&profile),
+    )
+    .await
+    {
+        Ok(player) => player,
+        Err(why) => {
+            error!(profile = %profile.name, error = %why, "Could not start profile");
             return;
         }
+    };
+
+    let player = Arc::new(Mutex::new(player));
+    session::supervise(player.clone());
+
+    let _ = started.send(player);
+}
 
-        let player = data.get::<SpotifyPlayerKey>().unwrap();
+/// Makes a started player available to the handlers, and starts casting right away when one of
+/// its users is in voice already.
+async fn adopt_player(ctx: &Context, player: Arc<Mutex<SpotifyPlayer>>) {
+    let name = player.lock().await.name.clone();
+
+    // Read the config now, it may have been reloaded while the player was connecting
+    let config = {
+        let mut data = ctx.data.write().await;
+        data.get_mut::<SpotifyPlayerKey>()
+            .unwrap()
+            .insert(name.clone(), player.clone());
+        data.get::<ConfigKey>().unwrap().clone()
+    };
+
+    player
+        .lock()
+        .await
+        .emitted_sink
+        .set_recording(config.recording(&name));
 
-        // If user just connected
-        if old.clone().is_none() {
-            // Enable casting
+    if let Some(profile) = config.profile(&name) {
+        if find_followed_channel(ctx, &config, &profile, &[]).is_some() {
             player.lock().await.enable_connect().await;
-            return;
         }
+    }
 
-        // If user disconnected
-        if old.clone().unwrap().channel_id.is_some() && new.channel_id.is_none() {
-            // Disable casting
-            ctx.invisible().await;
-            player.lock().await.disable_connect().await;
+    tokio::spawn(handle_player_events(ctx.clone(), player));
+}
 
-            // Disconnect
-            let manager = songbird::get(&ctx)
-                .await
-                .expect("Songbird Voice client placed in at initialization.")
-                .clone();
+/// Handles the Spotify events of one profile's player.
+async fn handle_player_events(c: Context, player: Arc<Mutex<SpotifyPlayer>>) {
+    let name = player.lock().await.name.clone();
 
-            let _handler = manager.remove(new.guild_id.unwrap()).await;
+    // Playing is also emitted on resume and seek, only announce actual track changes
+    let mut last_track_id = None;
 
-            return;
+    loop {
+        let channel = player.lock().await.event_channel.clone();
+        let mut receiver = channel.lock().await;
+
+        let event = match receiver.recv().await {
+            Some(e) => e,
+            None => {
+                // Busy waiting bad but quick and easy
+                sleep(Duration::from_millis(256)).await;
+                continue;
+            }
+        };
+
+        if let Err(why) = handle_player_event(&c, &player, event, &mut last_track_id).await {
+            warn!(profile = %name, error = %why, "Could not handle Spotify event");
         }
+    }
+}
 
-        // If user moved channels
-        if old.clone().unwrap().channel_id.unwrap() != new.channel_id.unwrap() {
-            let bot_id = ctx.cache.current_user_id();
-
-            // A bit hacky way to get old guild id because
-            // its not present when switching voice channels
-            // for the first time for some reason
-            let old_guild_id = match old.clone().unwrap().guild_id {
-                Some(gid) => gid,
-                None => ctx
-                    .cache
-                    .guilds()
-                    .iter()
-                    .find(|x| {
-                        ctx.cache
-                            .guild(**x)
-                            .unwrap()
-                            .channels
-                            .iter()
-                            .any(|ch| ch.1.id() == new.channel_id.unwrap())
-                    })
-                    .unwrap()
-                    .to_owned(),
+/// Whether `next` directly follows `current` on the same album. librespot doesn't tell which
+/// albums are gapless, but their tracks are meant to flow into each other in this order.
+async fn continues_album(session: &Session, current: SpotifyId, next: SpotifyId) -> bool {
+    let (Ok(current), Ok(next)) = (
+        Track::get(session, current).await,
+        Track::get(session, next).await,
+    ) else {
+        return false;
+    };
+
+    if current.album != next.album {
+        return false;
+    }
+
+    match Album::get(session, current.album).await {
+        Ok(album) => crossfade::follows_on(&album.tracks, current.id, next.id),
+        Err(_) => false,
+    }
+}
+
+async fn handle_player_event(
+    c: &Context,
+    player: &Mutex<SpotifyPlayer>,
+    event: PlayerEvent,
+    last_track_id: &mut Option<SpotifyId>,
+) -> Result<()> {
+    match event {
+        PlayerEvent::Stopped { .. } => {
+            c.set_presence(None, user::OnlineStatus::Online).await;
+
+            let manager = voice_manager(c).await?;
+
+            // Let the stream fade out before leaving the channel, songbird reads it a frame at
+            // a time
+            let fade = player.lock().await.emitted_sink.fade();
+            if !fade.is_zero() {
+                sleep(fade + Duration::from_millis(FRAME_LEN_MS as u64)).await;
+            }
+
+            let mut player = player.lock().await;
+            leave_all(&manager, &mut player).await;
+
+            let stats = player.emitted_sink.stats();
+            info!(
+                profile = %player.name,
+                underruns = stats.underruns(),
+                overruns = stats.overruns(),
+                "Audio buffer underruns and overruns so far"
+            );
+        }
+
+        PlayerEvent::Started { .. } => {
+            let manager = voice_manager(c).await?;
+
+            // Read the config on every start, it may have been reloaded since
+            let (config, players) = {
+                let data = c.data.read().await;
+                let config = data.get::<ConfigKey>().unwrap().clone();
+                (config, data.get::<SpotifyPlayerKey>().unwrap().clone())
             };
 
-            let bot_channel = ctx
-                .cache
-                .guild(old_guild_id)
-                .unwrap()
-                .voice_states
-                .get(&bot_id)
-                .and_then(|voice_state| voice_state.channel_id);
-
-            if Option::is_some(&bot_channel) {
-                let manager = songbird::get(&ctx)
-                    .await
-                    .expect("Songbird Voice client placed in at initialization.")
-                    .clone();
-
-                if old_guild_id != new.guild_id.unwrap() {
-                    let _handler = manager.remove(old_guild_id).await;
-                } else {
-                    let _handler = manager
-                        .join(new.guild_id.unwrap(), new.channel_id.unwrap())
-                        .await;
-                }
+            let name = player.lock().await.name.clone();
+            let Some(profile) = config.profile(&name) else {
+                return Ok(());
+            };
+
+            let occupied = occupied_guilds(&players, &name).await;
+
+            let Some((guild_id, channel_id)) =
+                find_followed_channel(c, &config, &profile, &occupied)
+            else {
+                info!(profile = %name, "Could not find a followed user in voice");
+                return Ok(());
+            };
+
+            play_in_channel(
+                &manager,
+                guild_id,
+                channel_id,
+                &config.guild(guild_id.0),
+                player,
+            )
+            .await?;
+
+            broadcast(c, &manager, &config, &profile, player, &occupied).await;
+        }
+
+        PlayerEvent::Paused { .. } => {
+            c.set_presence(None, user::OnlineStatus::Online).await;
+        }
+
+        PlayerEvent::Preloading { track_id } => {
+            let (session, sink) = {
+                let player = player.lock().await;
+                (player.session.clone(), player.emitted_sink.clone())
+            };
+
+            let Some(current) = *last_track_id else {
+                return Ok(());
+            };
+
+            if sink.crossfades() && continues_album(&session, current, track_id).await {
+                sink.cut_to(track_id);
             }
+        }
+
+        PlayerEvent::Playing { track_id, .. } => {
+            let announce = last_track_id.replace(track_id) != Some(track_id);
+
+            let session = player.lock().await.session.clone();
+
+            // Metadata lookups failing only costs us the presence and the recording's name,
+            // don't report it
+            let Ok(track) = Track::get(&session, track_id).await else {
+                return Ok(());
+            };
 
+            let artist = match track.artists.first() {
+                Some(artist_id) => Some(Artist::get(&session, *artist_id).await),
+                None => None,
+            };
+
+            // Without the artist, the recording is still named after the track
+            let artist_name = match &artist {
+                Some(Ok(artist)) => Some(artist.name.as_str()),
+                _ => None,
+            };
+            player
+                .lock()
+                .await
+                .emitted_sink
+                .name_recording(track_id, artist_name, &track.name);
+
+            let listening_to = match artist {
+                Some(Ok(artist)) => format!("{}: {}", artist.name, track.name),
+                Some(Err(_)) => return Ok(()),
+                None => track.name,
+            };
+
+            let guild_id = player.lock().await.guild_id;
+            if let (true, Some(guild_id)) = (announce, guild_id) {
+                announce_track(c, guild_id, &listening_to).await;
+            }
+
+            c.set_presence(
+                Some(gateway::Activity::listening(listening_to)),
+                user::OnlineStatus::Online,
+            )
+            .await;
+        }
+
+        _ => {}
+    }
+
+    Ok(())
+}
+
+async fn voice_manager(ctx: &Context) -> Result<Arc<Songbird>> {
+    songbird::get(ctx)
+        .await
+        .ok_or_else(|| AoedeError::Voice("Songbird was not registered with the client".to_string()))
+}
+
+/// Moves a profile's player to wherever its highest priority user is, enabling or disabling
+/// casting as users come and go.
+async fn follow_profile(
+    ctx: &Context,
+    manager: &Songbird,
+    config: &Config,
+    profile: &Profile,
+    player: &Mutex<SpotifyPlayer>,
+    players: &Players,
+) -> Result<()> {
+    let occupied = occupied_guilds(players, &profile.name).await;
+
+    let Some((guild_id, channel_id)) = find_followed_channel(ctx, config, profile, &occupied)
+    else {
+        // Nobody this profile follows is left in voice, disable casting and disconnect
+        let mut player = player.lock().await;
+        player.disable_connect().await;
+        leave_all(manager, &mut player).await;
+
+        return Ok(());
+    };
+
+    if !player.lock().await.is_connect_enabled() {
+        // Enable casting, the bot joins once playback starts
+        player.lock().await.enable_connect().await;
+        return Ok(());
+    }
+
+    // Follow the user if we are already in a call somewhere else
+    let Some(old_guild_id) = player.lock().await.guild_id else {
+        return Ok(());
+    };
+
+    let Some(handler_lock) = manager.get(old_guild_id) else {
+        return Ok(());
+    };
+
+    if handler_lock.lock().await.current_channel() == Some(channel_id.into()) {
+        return Ok(());
+    }
+
+    if old_guild_id != guild_id {
+        // Handing off to another guild, the stream has to be attached again
+        let _handler = manager.remove(old_guild_id).await;
+        player.lock().await.mixer.detach(old_guild_id);
+        play_in_channel(
+            manager,
+            guild_id,
+            channel_id,
+            &config.guild(guild_id.0),
+            player,
+        )
+        .await
+    } else {
+        let (_handler, joined) = manager.join(guild_id, channel_id).await;
+        joined.map_err(|why| AoedeError::Voice(format!("could not join channel: {}", why)))
+    }
+}
+
+/// Looks for the users of a profile in voice again, after they changed on reload.
+async fn refollow_profile(ctx: &Context, name: &str) {
+    let data = ctx.data.read().await;
+
+    let config = data.get::<ConfigKey>().unwrap();
+    let players = data.get::<SpotifyPlayerKey>().unwrap();
+
+    let (Some(profile), Some(player)) = (config.profile(name), players.get(name)) else {
+        return;
+    };
+
+    let manager = match voice_manager(ctx).await {
+        Ok(manager) => manager,
+        Err(why) => {
+            error!(error = %why, "Voice is unavailable");
             return;
         }
+    };
+
+    if let Err(why) = follow_profile(ctx, &manager, config, &profile, player, players).await {
+        warn!(profile = %profile.name, error = %why, "Could not follow profile");
+    }
+
+    if !any_connect_enabled(players).await {
+        ctx.invisible().await;
     }
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+/// Guilds that players of profiles other than `name` are streaming or broadcasting into.
+async fn occupied_guilds(players: &Players, name: &str) -> Vec<id::GuildId> {
+    let mut occupied = Vec::new();
+
+    for (other, player) in players {
+        if other != name {
+            let player = player.lock().await;
+            occupied.extend(player.guild_id);
+            occupied.extend(
+                player
+                    .broadcast_guilds
+                    .iter()
+                    .map(|(guild_id, _)| *guild_id),
+            );
+        }
+    }
 
-    let framework = StandardFramework::new();
+    occupied
+}
+
+/// Leaves the followed user's channel and every broadcast channel.
+async fn leave_all(manager: &Songbird, player: &mut SpotifyPlayer) {
+    let broadcast_guilds = player
+        .broadcast_guilds
+        .drain(..)
+        .map(|(guild_id, _)| guild_id);
+    for guild_id in player.guild_id.take().into_iter().chain(broadcast_guilds) {
+        let _ = manager.remove(guild_id).await;
+        player.mixer.detach(guild_id);
+    }
+}
+
+async fn any_connect_enabled(players: &Players) -> bool {
+    for player in players.values() {
+        if player.lock().await.is_connect_enabled() {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Finds the voice channel of the highest priority user of `profile` that is currently in voice.
+/// Priority is the order of `discord_user_ids`. Guilds in `occupied` and channels the guild
+/// config doesn't allow joining are skipped.
+fn find_followed_channel(
+    ctx: &Context,
+    config: &Config,
+    profile: &Profile,
+    occupied: &[id::GuildId],
+) -> Option<(id::GuildId, id::ChannelId)> {
+    let guilds = ctx.cache.guilds();
+
+    profile.discord_user_ids.iter().find_map(|user_id| {
+        guilds
+            .iter()
+            .filter(|guild_id| !occupied.contains(guild_id))
+            .find_map(|guild_id| {
+                ctx.cache
+                    .guild(guild_id)?
+                    .voice_states
+                    .get(&(*user_id).into())?
+                    .channel_id
+                    .filter(|channel_id| config.guild(guild_id.0).may_join(channel_id.0))
+                    .map(|channel_id| (guild_id.to_owned(), channel_id))
+            })
+    })
+}
+
+/// Posts the track to the announcement channel of the guild, if it has one.
+async fn announce_track(ctx: &Context, guild_id: id::GuildId, listening_to: &str) {
+    let config = ctx.data.read().await.get::<ConfigKey>().unwrap().clone();
+
+    if let Some(channel_id) = config.guild(guild_id.0).announcement_channel {
+        let message = format!("Now playing {}", listening_to);
+
+        if let Err(why) = id::ChannelId(channel_id).say(&ctx.http, message).await {
+            warn!(guild = %guild_id, error = %why, "Could not announce track");
+        }
+    }
+}
 
-    let config = match Config::new() {
+/// Joins the given voice channel and plays the Spotify stream into it.
+async fn play_in_channel(
+    manager: &Songbird,
+    guild_id: id::GuildId,
+    channel_id: id::ChannelId,
+    guild_config: &GuildConfig,
+    player: &Mutex<SpotifyPlayer>,
+) -> Result<()> {
+    let sink = player.lock().await.emitted_sink.clone();
+    let track = join_and_play(manager, guild_id, channel_id, sink).await?;
+
+    let mut player = player.lock().await;
+    player.guild_id = Some(guild_id);
+    player
+        .broadcast_guilds
+        .retain(|(other, _)| *other != guild_id);
+    player.emitted_sink.set_effects(&guild_config.effects);
+    player
+        .mixer
+        .attach(guild_id, track, guild_config.default_volume);
+
+    Ok(())
+}
+
+/// Plays the stream of `player` into the broadcast channels of `profile` as well, each reading
+/// from its own buffer. Guilds that are streamed into already are skipped.
+async fn broadcast(
+    ctx: &Context,
+    manager: &Songbird,
+    config: &Config,
+    profile: &Profile,
+    player: &Mutex<SpotifyPlayer>,
+    occupied: &[id::GuildId],
+) {
+    for channel_id in profile
+        .broadcast_channel_ids
+        .iter()
+        .copied()
+        .map(id::ChannelId)
+    {
+        let Some(guild_id) = ctx
+            .cache
+            .guild_channel(channel_id)
+            .map(|channel| channel.guild_id)
+        else {
+            warn!(profile = %profile.name, channel = %channel_id, "Could not find broadcast channel");
+            continue;
+        };
+
+        let sink = {
+            let player = player.lock().await;
+            if occupied.contains(&guild_id)
+                || player.guild_id == Some(guild_id)
+                || player
+                    .broadcast_guilds
+                    .iter()
+                    .any(|(other, _)| *other == guild_id)
+            {
+                info!(
+                    profile = %profile.name,
+                    channel = %channel_id,
+                    "Not broadcasting to channel, its guild is streamed into already"
+                );
+                continue;
+            }
+
+            player.emitted_sink.subscribe()
+        };
+
+        let guild_config = config.guild(guild_id.0);
+        sink.set_effects(&guild_config.effects);
+
+        match join_and_play(manager, guild_id, channel_id, sink.clone()).await {
+            Ok(track) => {
+                let mut player = player.lock().await;
+                player.broadcast_guilds.push((guild_id, sink));
+                player
+                    .mixer
+                    .attach(guild_id, track, guild_config.default_volume);
+            }
+            Err(why) => warn!(
+                profile = %profile.name,
+                channel = %channel_id,
+                error = %why,
+                "Could not broadcast to channel"
+            ),
+        }
+    }
+}
+
+/// Joins the given voice channel and plays `sink` into it.
+async fn join_and_play(
+    manager: &Songbird,
+    guild_id: id::GuildId,
+    channel_id: id::ChannelId,
+    sink: EmittedSink,
+) -> Result<TrackHandle> {
+    let (handler_lock, joined) = manager.join(guild_id, channel_id).await;
+    joined.map_err(|why| AoedeError::Voice(format!("could not join channel: {}", why)))?;
+
+    let mut handler = handler_lock.lock().await;
+
+    let mut decoder = input::codec::OpusDecoderState::new()
+        .map_err(|why| AoedeError::Voice(format!("could not create Opus decoder: {}", why)))?;
+    decoder.allow_passthrough = false;
+
+    let source = input::Input::new(
+        true,
+        input::reader::Reader::Extension(Box::new(sink)),
+        input::codec::Codec::FloatPcm,
+        input::Container::Raw,
+        None,
+    );
+
+    handler.set_bitrate(songbird::driver::Bitrate::Auto);
+
+    Ok(handler.play_only_source(source))
+}
+
+fn read_config() -> Config {
+    match Config::new() {
         Ok(config) => config,
         Err(error) => {
-            println!("Couldn't read config");
             if let MissingField(f) = error.kind {
-                println!("Missing field: '{}'", f.to_uppercase());
+                error!("Couldn't read config, missing field '{}'", f.to_uppercase());
             } else {
-                println!("Error: {:?}", error);
+                error!(error = %error, "Couldn't read config");
                 exit(2)
             }
             exit(1)
         }
-    };
+    }
+}
 
-    let mut cache_dir = None;
+/// Logs in once to cache reusable credentials, after which the password can be removed from the
+/// config. Without a profile name, every profile is logged in.
+async fn login(name: Option<&str>) {
+    let config = read_config();
+
+    let profiles: Vec<Profile> = config
+        .profiles()
+        .into_iter()
+        .filter(|profile| name.is_none_or(|name| profile.name == name))
+        .collect();
+
+    if profiles.is_empty() {
+        error!(
+            profile = name.unwrap_or_default(),
+            "No profile with this name"
+        );
+        exit(2)
+    }
 
-    if let Ok(c) = env::var("CACHE_DIR") {
-        cache_dir = Some(c);
+    for profile in profiles {
+        let password = match (profile.spotify_auth_mode, profile.spotify_password.clone()) {
+            (AuthMode::Discovery, _) => None,
+            (AuthMode::Password, Some(password)) => Some(password),
+            (AuthMode::Password, None) => Some(
+                rpassword::prompt_password(format!(
+                    "Spotify password for {} (profile '{}'): ",
+                    profile.spotify_username.clone().unwrap_or_default(),
+                    profile.name
+                ))
+                .unwrap_or_else(|why| {
+                    error!(profile = %profile.name, error = %why, "Could not read password");
+                    exit(1)
+                }),
+            ),
+        };
+
+        match session::login(&profile, password, config.cache_paths(&profile)).await {
+            Ok(dir) => info!(profile = %profile.name, "Logged in, credentials saved to {}", dir),
+            Err(why) => {
+                error!(profile = %profile.name, error = %why, "Could not log in");
+                exit(1)
+            }
+        }
     }
+}
 
-    let player = Arc::new(Mutex::new(
-        SpotifyPlayer::new(
-            config.spotify_username.clone(),
-            config.spotify_password.clone(),
-            Bitrate::Bitrate320,
-            cache_dir,
-            config.spotify_bot_autoplay,
-            config.spotify_device_name.clone(),
-        )
-        .await,
-    ));
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    // Aoede's own messages are shown unless RUST_LOG says otherwise
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("aoede=info"));
+
+    // The audio may go to stdout, keep everything else out of it
+    if args.starts_with(&["--output", "pipe"]) {
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    }
+
+    match args.as_slice() {
+        [] => {}
+        ["config", "--explain"] => match explain::explain() {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+                return;
+            }
+            Err(error) => {
+                error!(error = %error, "Couldn't read config");
+                exit(1)
+            }
+        },
+        ["login"] => {
+            login(None).await;
+            return;
+        }
+        ["login", profile] => {
+            login(Some(profile)).await;
+            return;
+        }
+        ["--output", "pipe", options @ ..] => {
+            let options = PipeOptions::parse(options).unwrap_or_else(|why| {
+                error!("{}", why);
+                exit(2)
+            });
+
+            let config = Config::headless().unwrap_or_else(|error| {
+                error!(error = %error, "Couldn't read config");
+                exit(1)
+            });
+
+            if let Err(why) = pipe::run(&config, options).await {
+                error!("{}", why);
+                exit(1)
+            }
+            return;
+        }
+        _ => {
+            error!(
+                "Usage: aoede [config --explain | login [<profile>] | --output pipe \
+                [--format raw|wav] [--sample f32|s16] [--path <file>] [--profile <name>]]"
+            );
+            exit(2)
+        }
+    }
+
+    let framework = StandardFramework::new();
+
+    let config = read_config();
+
+    // Profiles connect in the background, so one that can't doesn't hold up the others
+    let (started, started_players) = mpsc::unbounded_channel();
+    let (refollow, refollow_profiles) = mpsc::unbounded_channel();
+    for profile in config.profiles() {
+        tokio::spawn(start_profile(config.clone(), profile, started.clone()));
+    }
 
     let mut client = Client::builder(
         &config.discord_token,
@@ -316,14 +815,22 @@ async fn main() {
     )
     .event_handler(Handler)
     .framework(framework)
-    .type_map_insert::<SpotifyPlayerKey>(player)
+    .type_map_insert::<SpotifyPlayerKey>(HashMap::new())
+    .type_map_insert::<StartedPlayersKey>(Some(started_players))
+    .type_map_insert::<RefollowKey>(Some(refollow_profiles))
     .type_map_insert::<ConfigKey>(config)
     .register_songbird()
     .await
-    .expect("Err creating client");
+    .unwrap_or_else(|why| {
+        error!(error = %why, "Could not create client");
+        exit(1)
+    });
+
+    reload::spawn(client.data.clone(), refollow);
+    recorder::spawn_toggle(client.data.clone());
 
     let _ = client
         .start()
         .await
-        .map_err(|why| println!("Client ended: {:?}", why));
+        .map_err(|why| error!(error = %why, "Client ended"));
 }